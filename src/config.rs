@@ -0,0 +1,815 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{env, fs, io};
+
+use serde::{Deserialize, Deserializer};
+use serenity::prelude::TypeMapKey;
+use social_loaders::loaderror::LoadError;
+use social_loaders::retry::RetryPolicy;
+use social_loaders::AudioFormat;
+use url::Url;
+
+/// Used when neither the `--config` flag nor `$GAMERSBOT_CONFIG` is set
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/opt/gamersbot/properties.toml";
+pub const CONFIG_PATH_ENV: &str = "GAMERSBOT_CONFIG";
+
+/// Every env var starting with this prefix overrides the field of the same name,
+/// nested tables are separated by a double underscore: `GAMERSBOT_DOWNLOADERS__REDDIT=false`
+const ENV_PREFIX: &str = "GAMERSBOT_";
+const ENV_SEPARATOR: &str = "__";
+
+#[derive(Deserialize)]
+pub struct Config {
+    /// The channels whose links are reposted, keyed by channel id. The value is the url of a
+    /// webhook to repost with, without one the bot creates a webhook in the channel itself.
+    #[serde(deserialize_with = "channels_listening")]
+    pub channels_listening: HashMap<String, Option<String>>,
+    pub debug: u64,
+    pub discord_token: String,
+    pub downloaders: Downloaders,
+    #[serde(default)]
+    pub compression: Compression,
+    /// Links after this many in a single message are ignored
+    #[serde(default = "default_max_urls_per_message")]
+    pub max_urls_per_message: usize,
+    /// Who gets pinged by the mentions in a reposted message
+    #[serde(default)]
+    pub mentions: MentionPolicy,
+    /// Format of audio only downloads, of channels with `audio_only` and of `/download`
+    #[serde(default)]
+    pub audio_format: AudioFormat,
+    #[serde(default)]
+    pub jobs: Jobs,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(default)]
+    pub reposts: Reposts,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub feedback: Feedback,
+    /// Settings that only apply to a single channel, keyed by channel id
+    #[serde(default)]
+    pub channel_settings: HashMap<String, ChannelSettings>,
+    /// Replaces the message users get for an [error code](LoadError::code), for example to
+    /// translate them
+    #[serde(default)]
+    pub messages: HashMap<String, String>,
+}
+
+fn default_max_urls_per_message() -> usize {
+    5
+}
+
+/// `channels_listening` is either a list of channel ids or a table of channel ids to webhook urls
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "a list of channel ids or a table of channel ids to webhook urls"
+)]
+enum ChannelsListening {
+    Ids(Vec<ChannelKey>),
+    Webhooks(HashMap<String, String>),
+}
+
+/// Ids in the list can be written as numbers or as strings like the keys of the table
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChannelKey {
+    Number(u64),
+    Text(String),
+}
+
+fn channels_listening<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Option<String>>, D::Error> {
+    Ok(match ChannelsListening::deserialize(deserializer)? {
+        ChannelsListening::Ids(ids) => ids
+            .into_iter()
+            .map(|id| match id {
+                ChannelKey::Number(id) => (id.to_string(), None),
+                ChannelKey::Text(id) => (id, None),
+            })
+            .collect(),
+        ChannelsListening::Webhooks(urls) => urls
+            .into_iter()
+            .map(|(channel_id, url)| (channel_id, Some(url)))
+            .collect(),
+    })
+}
+
+/// Maps the [name](social_loaders::Downloader::name) of a downloader to whether it is turned on,
+/// downloaders missing from the table are off
+#[derive(Deserialize, Default)]
+#[serde(transparent)]
+pub struct Downloaders(HashMap<String, bool>);
+
+impl Downloaders {
+    pub fn enabled(&self, name: &str) -> bool {
+        self.0.get(name).copied().unwrap_or(false)
+    }
+}
+
+/// Re-encoding of videos that are over the upload limit, off unless turned on
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Compression {
+    pub enabled: bool,
+    /// Upper bound for all encoding passes of a single video
+    pub max_processing_secs: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            enabled: false,
+            max_processing_secs: 120,
+        }
+    }
+}
+
+/// Limits for the [job queue](crate::jobs::JobQueue), changes only take effect after a restart
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Jobs {
+    /// Downloads running at the same time over all platforms
+    pub max_concurrent: usize,
+    /// Downloads running at the same time for a single downloader, keyed by its name
+    pub per_platform: HashMap<String, usize>,
+    /// A job running longer than this is aborted
+    pub timeout_secs: u64,
+    /// How long running jobs get to finish when the bot shuts down
+    pub shutdown_grace_secs: u64,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Jobs {
+            max_concurrent: 2,
+            per_platform: HashMap::new(),
+            timeout_secs: 600,
+            shutdown_grace_secs: 60,
+        }
+    }
+}
+
+/// Remembers where media was uploaded to so the same post is not downloaded again, changes only
+/// take effect after a restart
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Cache {
+    pub enabled: bool,
+    /// Json file the entries are kept in between restarts
+    pub path: PathBuf,
+    /// Discord only serves attachment urls for about a day, older entries are useless
+    pub ttl_hours: u64,
+    pub max_entries: usize,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            enabled: false,
+            path: PathBuf::from("cache.json"),
+            ttl_hours: 20,
+            max_entries: 10_000,
+        }
+    }
+}
+
+/// Finds images and videos that were posted in the guild before, by comparing perceptual hashes.
+/// Changes only take effect after a restart.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Reposts {
+    pub enabled: bool,
+    /// Json file the hashes are kept in between restarts
+    pub path: PathBuf,
+    /// Most bits out of 64 two hashes may differ in to count as the same image
+    pub threshold: u32,
+    /// Only the newest posts of a guild are compared
+    pub max_per_guild: usize,
+}
+
+impl Default for Reposts {
+    fn default() -> Self {
+        Reposts {
+            enabled: false,
+            path: PathBuf::from("reposts.json"),
+            threshold: 10,
+            max_per_guild: 5000,
+        }
+    }
+}
+
+/// Trying downloads again that failed because of a hiccup of the platform or the connection
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Retry {
+    #[serde(flatten)]
+    pub default: RetryPolicy,
+    /// Policies for single downloaders keyed by their name, missing fields take the built in
+    /// default and not the one above
+    pub per_platform: HashMap<String, RetryPolicy>,
+}
+
+impl Retry {
+    pub fn policy(&self, platform: &str) -> &RetryPolicy {
+        self.per_platform.get(platform).unwrap_or(&self.default)
+    }
+}
+
+/// What users see of the download of their message while it runs
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Feedback {
+    /// Reactions on the message for queued, downloading, transcoding and failed
+    pub reactions: bool,
+    /// Reply to the message with the progress of long downloads and keep it up to date
+    pub status_message: bool,
+    /// Downloads that are done sooner get no status message
+    pub status_after_secs: u64,
+}
+
+impl Default for Feedback {
+    fn default() -> Self {
+        Feedback {
+            reactions: true,
+            status_message: false,
+            status_after_secs: 10,
+        }
+    }
+}
+
+/// What happens when someone posts something that was posted in the guild before
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepostAction {
+    /// Post it anyway and reply with a link to the original
+    Repost,
+    /// Post it with a reaction that marks it as a repost and reply with a link to the original
+    #[default]
+    Mark,
+    /// Do not post it, the bot only replies to the message with a link to the original
+    Block,
+}
+
+/// The repost comes from a webhook, so without a policy every `@everyone` in a reposted message
+/// would ping the whole server again
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MentionPolicy {
+    /// Nobody is pinged, the mentions are only shown
+    None,
+    /// Only users that were mentioned in the original message are pinged
+    #[default]
+    Users,
+    /// Users, roles and `@everyone` are pinged like in the original message
+    All,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ChannelSettings {
+    /// Overrides the upload limit in MB that we work out from the boost tier of the guild
+    pub max_upload_mb: Option<u16>,
+    pub repost_action: RepostAction,
+    /// Only post the sound of videos, for music and podcast channels
+    pub audio_only: bool,
+}
+
+/// The config is stored behind an [Arc] so handlers can take a snapshot and release the lock
+/// on the TypeMap right away, which lets a reload swap it without waiting for running downloads
+impl TypeMapKey for Config {
+    type Value = Arc<Config>;
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    Field {
+        field: String,
+        reason: String,
+    },
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "Could not read config file {}: {source}", path.display())
+            }
+            ConfigError::Parse { path, source } => {
+                write!(
+                    f,
+                    "Config file {} is not valid toml: {source}",
+                    path.display()
+                )
+            }
+            ConfigError::Field { field, reason } => {
+                write!(f, "Config field `{field}` is invalid: {reason}")
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file and applies the `GAMERSBOT_*` env overrides on top, the result is
+    /// validated so a broken file never replaces a working config
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        let mut table =
+            toml::from_str::<toml::Table>(&content).map_err(|source| ConfigError::Parse {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        apply_env_overrides(&mut table, env::vars())?;
+
+        Config::from_table(table)
+    }
+
+    fn from_table(table: toml::Table) -> Result<Config, ConfigError> {
+        let config: Config =
+            serde_path_to_error::deserialize(toml::Value::Table(table)).map_err(|err| {
+                ConfigError::Field {
+                    field: err.path().to_string(),
+                    reason: err.inner().to_string(),
+                }
+            })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (channel_id, webhook_url) in &self.channels_listening {
+            let field = format!("channels_listening.{channel_id}");
+            if channel_id.parse::<u64>().is_err() {
+                return Err(ConfigError::Field {
+                    field,
+                    reason: "the key has to be a discord channel id".into(),
+                });
+            }
+            let Some(webhook_url) = webhook_url else {
+                continue;
+            };
+            if let Err(err) = Url::parse(webhook_url) {
+                return Err(ConfigError::Field {
+                    field,
+                    reason: format!("webhook url is not a valid url: {err}"),
+                });
+            }
+        }
+        if self.jobs.max_concurrent == 0 {
+            return Err(ConfigError::Field {
+                field: "jobs.max_concurrent".into(),
+                reason: "at least one job has to be able to run".into(),
+            });
+        }
+        for (platform, limit) in &self.jobs.per_platform {
+            if *limit == 0 {
+                return Err(ConfigError::Field {
+                    field: format!("jobs.per_platform.{platform}"),
+                    reason: "at least one download has to be able to run".into(),
+                });
+            }
+        }
+        for (channel_id, settings) in &self.channel_settings {
+            let field = format!("channel_settings.{channel_id}");
+            if channel_id.parse::<u64>().is_err() {
+                return Err(ConfigError::Field {
+                    field,
+                    reason: "the key has to be a discord channel id".into(),
+                });
+            }
+            if settings.max_upload_mb == Some(0) {
+                return Err(ConfigError::Field {
+                    field: field + ".max_upload_mb",
+                    reason: "the upload limit has to be at least 1MB".into(),
+                });
+            }
+        }
+        let policies = self
+            .retry
+            .per_platform
+            .iter()
+            .map(|(platform, policy)| (format!("retry.per_platform.{platform}"), policy))
+            .chain([("retry".to_string(), &self.retry.default)]);
+        for (field, policy) in policies {
+            if policy.max_attempts == 0 {
+                return Err(ConfigError::Field {
+                    field: field + ".max_attempts",
+                    reason: "the first attempt counts too, it has to be at least 1".into(),
+                });
+            }
+        }
+        for code in self.messages.keys() {
+            if !LoadError::CODES.contains(&code.as_str()) {
+                return Err(ConfigError::Field {
+                    field: format!("messages.{code}"),
+                    reason: format!("unknown error code, use one of {:?}", LoadError::CODES),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// What the user gets told about the error, in the words of the `[messages]` table when it
+    /// has one for the code
+    pub fn error_message(&self, err: &LoadError) -> String {
+        match self.messages.get(err.code()) {
+            Some(template) => err.render(template),
+            None => err.user_message(),
+        }
+    }
+}
+
+/// Returns the config path given on the command line, then `$GAMERSBOT_CONFIG` and
+/// lastly [DEFAULT_CONFIG_PATH]
+pub fn locate() -> PathBuf {
+    config_path(env::args().skip(1), env::var(CONFIG_PATH_ENV).ok())
+}
+
+fn config_path(mut args: impl Iterator<Item = String>, env_path: Option<String>) -> PathBuf {
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            if let Some(path) = args.next() {
+                return PathBuf::from(path);
+            }
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return PathBuf::from(path);
+        }
+    }
+
+    env_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    for (key, value) in vars {
+        if key == CONFIG_PATH_ENV {
+            continue;
+        }
+        let Some(field) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+
+        let path = field
+            .split(ENV_SEPARATOR)
+            .map(str::to_lowercase)
+            .collect::<Vec<String>>();
+        let Some((last, parents)) = path.split_last() else {
+            continue;
+        };
+
+        let mut current = &mut *table;
+        for (depth, parent) in parents.iter().enumerate() {
+            let entry = current
+                .entry(parent.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            let Some(child) = entry.as_table_mut() else {
+                return Err(ConfigError::Field {
+                    field: path[..=depth].join("."),
+                    reason: format!("{key} sets a field in it, but it is not a table"),
+                });
+            };
+            current = child;
+        }
+        let value = parse_env_value(&path.join("."), &value, current.get(last))?;
+        current.insert(last.clone(), value);
+    }
+    Ok(())
+}
+
+/// Env vars are untyped so the value is read as the type of the field it replaces. Strings like
+/// tokens and urls are taken as they are, numbers, booleans and arrays are written like in toml.
+/// A field missing from the file gets whatever the value looks like.
+fn parse_env_value(
+    field: &str,
+    raw: &str,
+    replaced: Option<&toml::Value>,
+) -> Result<toml::Value, ConfigError> {
+    let parsed = toml::from_str::<toml::Table>(&format!("value = {raw}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"));
+    let expected = match replaced {
+        None => return Ok(parsed.unwrap_or_else(|| toml::Value::String(raw.to_string()))),
+        Some(toml::Value::String(_)) => return Ok(toml::Value::String(raw.to_string())),
+        Some(toml::Value::Table(_)) => {
+            return Err(ConfigError::Field {
+                field: field.to_string(),
+                reason: "it is a table, override its fields one by one".into(),
+            })
+        }
+        Some(value) => value.type_str(),
+    };
+    match parsed {
+        Some(value) if value.type_str() == expected => Ok(value),
+        _ => Err(ConfigError::Field {
+            field: field.to_string(),
+            reason: format!("`{raw}` is not a {expected} like the value in the config file"),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        debug = 1
+        discord_token = "token"
+
+        [channels_listening]
+        123 = "https://discord.com/api/webhooks/1/abc"
+
+        [downloaders]
+        reddit = true
+        youtube = true
+        tumblr = false
+        tiktok = false
+    "#;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_config_path_precedence() {
+        let args = |a: &[&str]| {
+            a.iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+
+        assert_eq!(
+            config_path(args(&["--config", "a.toml"]), Some("b.toml".into())),
+            PathBuf::from("a.toml")
+        );
+        assert_eq!(
+            config_path(args(&["--config=a.toml"]), None),
+            PathBuf::from("a.toml")
+        );
+        assert_eq!(
+            config_path(args(&[]), Some("b.toml".into())),
+            PathBuf::from("b.toml")
+        );
+        assert_eq!(
+            config_path(args(&[]), None),
+            PathBuf::from(DEFAULT_CONFIG_PATH)
+        );
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars(&[
+                ("GAMERSBOT_DISCORD_TOKEN", "rotated.token-value"),
+                ("GAMERSBOT_DEBUG", "42"),
+                ("GAMERSBOT_DOWNLOADERS__TIKTOK", "true"),
+                ("GAMERSBOT_MENTIONS", "none"),
+                ("GAMERSBOT_CONFIG", "/somewhere/else.toml"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+
+        let config = Config::from_table(table).unwrap();
+        assert_eq!(config.discord_token, "rotated.token-value");
+        assert_eq!(config.debug, 42);
+        assert!(config.downloaders.enabled("tiktok"));
+        assert!(config.downloaders.enabled("reddit"));
+        assert!(!config.downloaders.enabled("tumblr"));
+        assert_eq!(config.max_urls_per_message, 5);
+        assert_eq!(config.mentions, MentionPolicy::None);
+    }
+
+    #[test]
+    fn test_invalid_field_is_reported() {
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        let result = apply_env_overrides(
+            &mut table,
+            vars(&[("GAMERSBOT_DOWNLOADERS__REDDIT", "yes")]),
+        );
+
+        match result {
+            Err(ConfigError::Field { field, .. }) => assert_eq!(field, "downloaders.reddit"),
+            _ => panic!("Expected a field error for downloaders.reddit"),
+        }
+    }
+
+    #[test]
+    fn test_env_values_keep_the_type_of_the_file() {
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(&mut table, vars(&[("GAMERSBOT_DISCORD_TOKEN", "12345")])).unwrap();
+        assert_eq!(Config::from_table(table).unwrap().discord_token, "12345");
+
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        let result = apply_env_overrides(&mut table, vars(&[("GAMERSBOT_DEBUG", "abc")]));
+        match result {
+            Err(ConfigError::Field { field, .. }) => assert_eq!(field, "debug"),
+            _ => panic!("Expected a field error for debug"),
+        }
+
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        let result = apply_env_overrides(&mut table, vars(&[("GAMERSBOT_DOWNLOADERS", "true")]));
+        match result {
+            Err(ConfigError::Field { field, .. }) => assert_eq!(field, "downloaders"),
+            _ => panic!("Expected a field error for downloaders"),
+        }
+    }
+
+    #[test]
+    fn test_env_override_into_channel_list_is_reported() {
+        let mut table = toml::from_str::<toml::Table>(&CONFIG.replace(
+            r#"[channels_listening]
+        123 = "https://discord.com/api/webhooks/1/abc""#,
+            r#"channels_listening = [123]"#,
+        ))
+        .unwrap();
+        let result = apply_env_overrides(
+            &mut table,
+            vars(&[(
+                "GAMERSBOT_CHANNELS_LISTENING__456",
+                "https://discord.com/api/webhooks/2/def",
+            )]),
+        );
+        match result {
+            Err(ConfigError::Field { field, .. }) => assert_eq!(field, "channels_listening"),
+            _ => panic!("Expected a field error for channels_listening"),
+        }
+
+        apply_env_overrides(
+            &mut table,
+            vars(&[("GAMERSBOT_CHANNELS_LISTENING", "[123, 456]")]),
+        )
+        .unwrap();
+        let config = Config::from_table(table).unwrap();
+        assert_eq!(config.channels_listening.get("456"), Some(&None));
+    }
+
+    #[test]
+    fn test_invalid_webhook_url_is_reported() {
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars(&[("GAMERSBOT_CHANNELS_LISTENING__123", "not a url")]),
+        )
+        .unwrap();
+
+        match Config::from_table(table) {
+            Err(ConfigError::Field { field, .. }) => assert_eq!(field, "channels_listening.123"),
+            _ => panic!("Expected a field error for channels_listening.123"),
+        }
+    }
+
+    #[test]
+    fn test_channels_without_webhooks() {
+        let table = toml::from_str::<toml::Table>(&CONFIG.replace(
+            r#"[channels_listening]
+        123 = "https://discord.com/api/webhooks/1/abc""#,
+            r#"channels_listening = [123, "456"]"#,
+        ))
+        .unwrap();
+        let config = Config::from_table(table).unwrap();
+        assert_eq!(config.channels_listening.get("123"), Some(&None));
+        assert_eq!(config.channels_listening.get("456"), Some(&None));
+
+        let table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        let config = Config::from_table(table).unwrap();
+        assert_eq!(
+            config.channels_listening.get("123"),
+            Some(&Some("https://discord.com/api/webhooks/1/abc".to_string()))
+        );
+
+        let table = toml::from_str::<toml::Table>(&CONFIG.replace(
+            r#"[channels_listening]
+        123 = "https://discord.com/api/webhooks/1/abc""#,
+            r#"channels_listening = ["general"]"#,
+        ))
+        .unwrap();
+        match Config::from_table(table) {
+            Err(ConfigError::Field { field, .. }) => {
+                assert_eq!(field, "channels_listening.general")
+            }
+            _ => panic!("Expected a field error for channels_listening.general"),
+        }
+    }
+
+    #[test]
+    fn test_channel_settings() {
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars(&[
+                ("GAMERSBOT_CHANNEL_SETTINGS__123__MAX_UPLOAD_MB", "25"),
+                ("GAMERSBOT_AUDIO_FORMAT", "opus"),
+            ]),
+        )
+        .unwrap();
+        let config = Config::from_table(table).unwrap();
+        assert_eq!(config.channel_settings["123"].max_upload_mb, Some(25));
+        assert_eq!(
+            config.channel_settings["123"].repost_action,
+            RepostAction::Mark
+        );
+        assert!(!config.channel_settings["123"].audio_only);
+        assert_eq!(config.audio_format, AudioFormat::Opus);
+
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars(&[("GAMERSBOT_CHANNEL_SETTINGS__123__MAX_UPLOAD_MB", "0")]),
+        )
+        .unwrap();
+        match Config::from_table(table) {
+            Err(ConfigError::Field { field, .. }) => {
+                assert_eq!(field, "channel_settings.123.max_upload_mb")
+            }
+            _ => panic!("Expected a field error for channel_settings.123.max_upload_mb"),
+        }
+    }
+
+    #[test]
+    fn test_retry_policies() {
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars(&[
+                ("GAMERSBOT_RETRY__MAX_ATTEMPTS", "5"),
+                (
+                    "GAMERSBOT_RETRY__PER_PLATFORM__YOUTUBE__MAX_DELAY_MS",
+                    "60000",
+                ),
+            ]),
+        )
+        .unwrap();
+        let config = Config::from_table(table).unwrap();
+        assert_eq!(config.retry.policy("reddit").max_attempts, 5);
+        assert_eq!(config.retry.policy("youtube").max_attempts, 3);
+        assert_eq!(config.retry.policy("youtube").max_delay_ms, 60_000);
+
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars(&[("GAMERSBOT_RETRY__PER_PLATFORM__REDDIT__MAX_ATTEMPTS", "0")]),
+        )
+        .unwrap();
+        match Config::from_table(table) {
+            Err(ConfigError::Field { field, .. }) => {
+                assert_eq!(field, "retry.per_platform.reddit.max_attempts")
+            }
+            _ => panic!("Expected a field error for retry.per_platform.reddit.max_attempts"),
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars(&[("GAMERSBOT_MESSAGES__NOT_FOUND", "Den Post gibt es nicht")]),
+        )
+        .unwrap();
+        let config = Config::from_table(table).unwrap();
+        assert_eq!(
+            config.error_message(&LoadError::NotFound),
+            "Den Post gibt es nicht"
+        );
+        assert_eq!(
+            config.error_message(&LoadError::Private),
+            LoadError::Private.user_message()
+        );
+
+        let mut table = toml::from_str::<toml::Table>(CONFIG).unwrap();
+        apply_env_overrides(&mut table, vars(&[("GAMERSBOT_MESSAGES__GONE", "Weg")])).unwrap();
+        match Config::from_table(table) {
+            Err(ConfigError::Field { field, .. }) => assert_eq!(field, "messages.gone"),
+            _ => panic!("Expected a field error for messages.gone"),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::json_file::JsonFile;
+
+/// Remembers the Discord CDN urls of media we already uploaded, keyed by platform and post id.
+/// When the same post shows up again we post the urls instead of downloading and converting the
+/// files a second time. The entries are written to a json file so they survive a restart.
+pub struct Cache {
+    file: JsonFile,
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Entry {
+    attachment_urls: Vec<String>,
+    /// Perceptual hashes of every attachment, see [phash](crate::phash). Entries written before
+    /// they were kept have none.
+    #[serde(default)]
+    hashes: Vec<Vec<u64>>,
+    /// Unix time in seconds
+    created: u64,
+    last_used: u64,
+}
+
+/// What we remember about an earlier upload
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cached {
+    /// The CDN urls of the media, in the order they were uploaded
+    pub attachment_urls: Vec<String>,
+    /// The hashes of every attachment in the same order, a cache hit can still be checked for
+    /// reposts with them
+    pub hashes: Vec<Vec<u64>>,
+}
+
+/// Key of the media of a post, `media_id` comes from [Downloader::media_id](crate::Downloader)
+pub fn key(platform: &str, media_id: &str) -> String {
+    format!("{platform}:{media_id}")
+}
+
+impl Cache {
+    /// Loads the entries from `path`, a missing or broken file gives an empty cache
+    pub fn open(path: &Path, ttl: Duration, max_entries: usize) -> Cache {
+        let file = JsonFile::new(path, "cache");
+        let entries = file.read();
+
+        let cache = Cache {
+            file,
+            ttl,
+            max_entries,
+            entries: Mutex::new(entries),
+        };
+        let mut entries = cache.entries.lock().unwrap();
+        cache.evict(&mut entries, now());
+        info!(
+            "Loaded {} cache entries from {}",
+            entries.len(),
+            path.display()
+        );
+        drop(entries);
+        cache
+    }
+
+    pub fn get(&self, key: &str) -> Option<Cached> {
+        self.get_at(key, now())
+    }
+
+    pub fn insert(&self, key: String, attachment_urls: Vec<String>, hashes: Vec<Vec<u64>>) {
+        self.insert_at(key, attachment_urls, hashes, now())
+    }
+
+    fn get_at(&self, key: &str, now: u64) -> Option<Cached> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        if self.is_expired(entry, now) {
+            entries.remove(key);
+            return None;
+        }
+        entry.last_used = now;
+        Some(Cached {
+            attachment_urls: entry.attachment_urls.clone(),
+            hashes: entry.hashes.clone(),
+        })
+    }
+
+    fn insert_at(
+        &self,
+        key: String,
+        attachment_urls: Vec<String>,
+        hashes: Vec<Vec<u64>>,
+        now: u64,
+    ) {
+        if attachment_urls.is_empty() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(
+            key,
+            Entry {
+                attachment_urls,
+                hashes,
+                created: now,
+                last_used: now,
+            },
+        );
+        self.evict(&mut entries, now);
+        self.file.write(&*entries);
+    }
+
+    fn is_expired(&self, entry: &Entry, now: u64) -> bool {
+        now.saturating_sub(entry.created) >= self.ttl.as_secs()
+    }
+
+    /// Drops expired entries and then the least recently used ones until `max_entries` fit
+    fn evict(&self, entries: &mut HashMap<String, Entry>, now: u64) {
+        entries.retain(|_, entry| !self.is_expired(entry, now));
+        if entries.len() <= self.max_entries {
+            return;
+        }
+
+        let mut by_use = entries
+            .iter()
+            .map(|(key, entry)| (entry.last_used, key.clone()))
+            .collect::<Vec<(u64, String)>>();
+        by_use.sort();
+        let surplus = entries.len() - self.max_entries;
+        for (_, key) in by_use.into_iter().take(surplus) {
+            entries.remove(&key);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::cache::{key, Cache, Cached};
+
+    fn cache(name: &str, max_entries: usize) -> Cache {
+        let path = std::env::temp_dir().join(format!("social_loaders_{name}.json"));
+        let _ = std::fs::remove_file(&path);
+        Cache::open(&path, Duration::from_secs(100), max_entries)
+    }
+
+    fn urls(name: &str) -> Vec<String> {
+        vec![format!(
+            "https://cdn.discordapp.com/attachments/1/2/{name}.mp4"
+        )]
+    }
+
+    fn cached(name: &str) -> Cached {
+        Cached {
+            attachment_urls: urls(name),
+            hashes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let cache = cache("expire", 10);
+        cache.insert_at(key("reddit", "abc"), urls("a"), Vec::new(), 1000);
+
+        assert_eq!(cache.get_at("reddit:abc", 1099), Some(cached("a")));
+        assert_eq!(cache.get_at("reddit:abc", 1100), None);
+        assert_eq!(cache.get_at("youtube:abc", 1000), None);
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = cache("evict", 2);
+        cache.insert_at("a".into(), urls("a"), Vec::new(), 1000);
+        cache.insert_at("b".into(), urls("b"), Vec::new(), 1001);
+        cache.get_at("a", 1002);
+        cache.insert_at("c".into(), urls("c"), Vec::new(), 1003);
+
+        assert!(cache.get_at("a", 1004).is_some());
+        assert!(cache.get_at("b", 1004).is_none());
+        assert!(cache.get_at("c", 1004).is_some());
+    }
+
+    #[test]
+    fn test_survives_restart() {
+        let cache = cache("restart", 10);
+        cache.insert(key("tiktok", "123"), urls("a"), vec![vec![0xF0F0, 0x0F0F]]);
+
+        let reopened = Cache::open(cache.file.path(), Duration::from_secs(100), 10);
+        let expected = Cached {
+            attachment_urls: urls("a"),
+            hashes: vec![vec![0xF0F0, 0x0F0F]],
+        };
+        assert_eq!(reopened.get("tiktok:123"), Some(expected));
+        let _ = std::fs::remove_file(cache.file.path());
+    }
+
+    #[test]
+    fn test_entries_without_hashes_still_load() {
+        let path = std::env::temp_dir().join("social_loaders_old_entries.json");
+        let entry =
+            r#"{"reddit:abc":{"attachment_urls":["a.mp4"],"created":1000,"last_used":1000}}"#;
+        std::fs::write(&path, entry).unwrap();
+
+        let cache = Cache::open(&path, Duration::from_secs(u64::MAX), 10);
+        let cached = cache.get("reddit:abc").unwrap();
+        assert_eq!(cached.attachment_urls, vec!["a.mp4".to_string()]);
+        assert!(cached.hashes.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,309 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::process::Command;
+use tokio::time::Instant;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::loaderror::{tool_failed, LoadError, LoadResult};
+use crate::tool;
+
+/// Resolutions we try from best to worst together with the video bitrate in kbit/s below which
+/// the result looks too bad to be worth posting
+const PRESETS: [Preset; 4] = [
+    Preset {
+        height: 720,
+        min_video_kbps: 1500,
+        audio_kbps: 128,
+    },
+    Preset {
+        height: 480,
+        min_video_kbps: 700,
+        audio_kbps: 96,
+    },
+    Preset {
+        height: 360,
+        min_video_kbps: 350,
+        audio_kbps: 64,
+    },
+    Preset {
+        height: 240,
+        min_video_kbps: 150,
+        audio_kbps: 48,
+    },
+];
+
+/// The container and the muxer add a bit on top of the bitrate we ask for
+const OVERHEAD_FACTOR: f64 = 0.95;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Preset {
+    height: u32,
+    min_video_kbps: u64,
+    audio_kbps: u64,
+}
+
+#[derive(Debug, PartialEq)]
+struct EncodePlan {
+    height: u32,
+    video_kbps: u64,
+    audio_kbps: u64,
+}
+
+/// Whether we know how to re-encode the file, images are never compressed
+pub fn is_video(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("mp4" | "webm" | "mov" | "mkv")
+    )
+}
+
+/// Re-encodes the video so it is smaller than `max_bytes`. The bitrate is calculated from the
+/// duration of the video and the video is encoded in two passes, when the bitrate is too low for
+/// the current resolution the video is downscaled. Gives up when the smallest preset does not fit
+/// or the encoding takes longer than `max_processing_time`.
+pub async fn compress_to_fit(
+    path: &Path,
+    max_bytes: u64,
+    max_processing_time: Duration,
+) -> LoadResult<PathBuf> {
+    let deadline = Instant::now() + max_processing_time;
+    let duration = probe_duration(path).await?;
+
+    let mut plan = plan(duration, max_bytes, &PRESETS);
+    while let Some(current) = plan {
+        info!(
+            "Compressing {} to {}p with {}k video and {}k audio",
+            path.display(),
+            current.height,
+            current.video_kbps,
+            current.audio_kbps
+        );
+        let Ok(compressed) = tokio::time::timeout_at(deadline, encode(path, &current)).await else {
+            info!(
+                "Compressing {} took longer than {} seconds",
+                path.display(),
+                max_processing_time.as_secs()
+            );
+            return Err(too_large(path, max_bytes));
+        };
+        let compressed = compressed?;
+
+        let size = compressed.metadata()?.len();
+        if size <= max_bytes {
+            return Ok(compressed);
+        }
+
+        //The encoder overshot the bitrate, try again one preset smaller
+        info!("Compressed file is still {size} bytes, trying a smaller preset");
+        let _ = std::fs::remove_file(&compressed);
+        let smaller = PRESETS
+            .iter()
+            .filter(|preset| preset.height < current.height)
+            .copied()
+            .collect::<Vec<Preset>>();
+        plan = self::plan(duration, max_bytes, &smaller);
+    }
+
+    info!(
+        "{} is too long to be compressed below {max_bytes} bytes",
+        path.display()
+    );
+    Err(too_large(path, max_bytes))
+}
+
+/// The video stays as large as it was
+fn too_large(path: &Path, max_bytes: u64) -> LoadError {
+    LoadError::TooLarge {
+        size: path.metadata().ok().map(|metadata| metadata.len()),
+        limit: max_bytes,
+    }
+}
+
+/// Picks the largest preset whose minimum bitrate still fits into `max_bytes` for the duration
+fn plan(duration_secs: f64, max_bytes: u64, presets: &[Preset]) -> Option<EncodePlan> {
+    if duration_secs <= 0.0 {
+        return None;
+    }
+    let total_kbps = (max_bytes as f64 * 8.0 * OVERHEAD_FACTOR / duration_secs / 1000.0) as u64;
+
+    presets.iter().find_map(|preset| {
+        let video_kbps = total_kbps.checked_sub(preset.audio_kbps)?;
+        (video_kbps >= preset.min_video_kbps).then_some(EncodePlan {
+            height: preset.height,
+            video_kbps,
+            audio_kbps: preset.audio_kbps,
+        })
+    })
+}
+
+async fn probe_duration(path: &Path) -> LoadResult<f64> {
+    let output = Command::new(tool("ffprobe"))
+        .args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    parse_duration(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| tool_failed("ffprobe", &output))
+}
+
+fn parse_duration(output: &str) -> Option<f64> {
+    output.trim().parse::<f64>().ok().filter(|secs| *secs > 0.0)
+}
+
+/// The files of an encode. They are removed when it fails or is dropped half way because it ran
+/// into the deadline, only a finished output is kept.
+struct EncodeFiles {
+    passlog: PathBuf,
+    output: PathBuf,
+    finished: bool,
+}
+
+impl Drop for EncodeFiles {
+    fn drop(&mut self) {
+        //x264 writes its stats next to the passlog prefix
+        for suffix in ["-0.log", "-0.log.mbtree"] {
+            let mut log = self.passlog.clone().into_os_string();
+            log.push(suffix);
+            let _ = std::fs::remove_file(log);
+        }
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.output);
+        }
+    }
+}
+
+async fn encode(path: &Path, plan: &EncodePlan) -> LoadResult<PathBuf> {
+    let working_dir = path.parent().unwrap_or(Path::new("."));
+    let name = Uuid::new_v4().to_string();
+    let mut files = EncodeFiles {
+        passlog: working_dir.join(&name),
+        output: working_dir.join(name + ".mp4"),
+        finished: false,
+    };
+    let (passlog, output) = (&files.passlog, &files.output);
+
+    //Only ever scale down, never blow up a small video
+    let scale = format!("scale=-2:'min({},ih)'", plan.height);
+    let video_bitrate = format!("{}k", plan.video_kbps);
+
+    let first_pass = Command::new(tool("ffmpeg"))
+        .args(["-y", "-i"])
+        .arg(path)
+        .args(["-c:v", "libx264", "-b:v", &video_bitrate, "-vf", &scale])
+        .args(["-pass", "1", "-passlogfile"])
+        .arg(passlog)
+        .args(["-an", "-f", "null", "-"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output();
+    run_ffmpeg(first_pass).await?;
+    let second_pass = Command::new(tool("ffmpeg"))
+        .args(["-y", "-i"])
+        .arg(path)
+        .args(["-c:v", "libx264", "-b:v", &video_bitrate, "-vf", &scale])
+        .args(["-pass", "2", "-passlogfile"])
+        .arg(passlog)
+        .args(["-c:a", "aac", "-b:a", &format!("{}k", plan.audio_kbps)])
+        .args(["-movflags", "+faststart"])
+        .arg(output)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output();
+    run_ffmpeg(second_pass).await?;
+
+    files.finished = true;
+    Ok(files.output.clone())
+}
+
+async fn run_ffmpeg(
+    command: impl std::future::Future<Output = std::io::Result<std::process::Output>>,
+) -> LoadResult<()> {
+    let output = command.await?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(tool_failed("ffmpeg", &output)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::compress::{is_video, parse_duration, plan, EncodeFiles, EncodePlan, PRESETS};
+
+    #[test]
+    fn test_plan_keeps_resolution_for_short_videos() {
+        //30 seconds into 8MB leaves about 2000kbit/s
+        assert_eq!(
+            plan(30.0, 8_000_000, &PRESETS),
+            Some(EncodePlan {
+                height: 720,
+                video_kbps: 1898,
+                audio_kbps: 128,
+            })
+        );
+    }
+
+    #[test]
+    fn test_plan_downscales_longer_videos() {
+        //90 seconds into 8MB leaves about 675kbit/s, not enough for 720p or 480p
+        assert_eq!(plan(90.0, 8_000_000, &PRESETS).map(|p| p.height), Some(360));
+        assert_eq!(
+            plan(300.0, 8_000_000, &PRESETS).map(|p| p.height),
+            Some(240)
+        );
+    }
+
+    #[test]
+    fn test_plan_rejects_when_smallest_preset_does_not_fit() {
+        assert_eq!(plan(1200.0, 8_000_000, &PRESETS), None);
+        assert_eq!(plan(0.0, 8_000_000, &PRESETS), None);
+    }
+
+    #[test]
+    fn test_parse_ffprobe_duration() {
+        assert_eq!(parse_duration("14.033333\n"), Some(14.033333));
+        assert_eq!(parse_duration("N/A\n"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn test_only_videos_are_compressed() {
+        assert!(is_video(Path::new("/tmp/a.mp4")));
+        assert!(!is_video(Path::new("/tmp/a.jpg")));
+        assert!(!is_video(Path::new("/tmp/a")));
+    }
+
+    #[test]
+    fn test_unfinished_encode_leaves_no_files() {
+        let dir = std::env::temp_dir();
+        let passlog = dir.join("social_loaders_encode");
+        let output = dir.join("social_loaders_encode.mp4");
+        let log = dir.join("social_loaders_encode-0.log");
+        let files = |finished| {
+            for path in [&output, &log] {
+                std::fs::write(path, "").unwrap();
+            }
+            EncodeFiles {
+                passlog: passlog.clone(),
+                output: output.clone(),
+                finished,
+            }
+        };
+
+        drop(files(false));
+        assert!(!output.exists() && !log.exists());
+
+        drop(files(true));
+        assert!(output.exists() && !log.exists());
+        let _ = std::fs::remove_file(&output);
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::{error, warn};
+
+/// A json file the [Cache](crate::cache::Cache) and the [RepostStore](crate::phash::RepostStore)
+/// keep their entries in. Writing happens on a blocking thread, so the lock of the entries is
+/// only held while they are serialized and not while the disk is slow.
+pub(crate) struct JsonFile {
+    path: PathBuf,
+    /// Named in the logs, like "cache"
+    what: &'static str,
+    /// Counts the writes, a write that is overtaken by a newer one is skipped
+    version: AtomicU64,
+    /// Version that is on disk, also keeps two writes from sharing the temporary file
+    written: Arc<Mutex<u64>>,
+}
+
+impl JsonFile {
+    pub(crate) fn new(path: &Path, what: &'static str) -> JsonFile {
+        JsonFile {
+            path: path.to_path_buf(),
+            what,
+            version: AtomicU64::new(0),
+            written: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The content of the file, a missing or broken file gives the default
+    pub(crate) fn read<T: DeserializeOwned + Default>(&self) -> T {
+        match fs::read(&self.path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|err| {
+                warn!(
+                    "The {} file {} is broken, starting empty: {err}",
+                    self.what,
+                    self.path.display()
+                );
+                T::default()
+            }),
+            Err(_) => T::default(),
+        }
+    }
+
+    /// Has to be called while the lock of `value` is held, that way the versions are in the
+    /// order of the changes. Outside of a tokio runtime the file is written right away.
+    pub(crate) fn write(&self, value: &impl Serialize) {
+        let content = match serde_json::to_vec(value) {
+            Ok(content) => content,
+            Err(err) => {
+                error!("Could not serialize the {} file: {err}", self.what);
+                return;
+            }
+        };
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        let (path, what, written) = (self.path.clone(), self.what, self.written.clone());
+        let write = move || {
+            let mut written = written.lock().unwrap();
+            if *written > version {
+                return;
+            }
+            //A crash while writing never leaves a broken file
+            let temp = path.with_extension("tmp");
+            let result = fs::write(&temp, content).and_then(|_| fs::rename(temp, &path));
+            match result {
+                Ok(_) => *written = version,
+                Err(err) => error!("Could not write {what} file {}: {err}", path.display()),
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::json_file::JsonFile;
+
+    #[tokio::test]
+    async fn test_newest_write_ends_up_on_disk() {
+        let path = std::env::temp_dir().join("social_loaders_json_file.json");
+        let _ = std::fs::remove_file(&path);
+        let file = JsonFile::new(&path, "test");
+
+        for i in 0..20 {
+            file.write(&vec![i]);
+        }
+        for _ in 0..100 {
+            if file.read::<Vec<u32>>() == vec![19] {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(file.read::<Vec<u32>>(), vec![19]);
+        assert!(!path.with_extension("tmp").exists());
+        let _ = std::fs::remove_file(&path);
+    }
+}
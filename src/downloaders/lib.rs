@@ -1,41 +1,352 @@
 #![feature(once_cell_try)]
 
 use std::env::temp_dir;
-use std::fs;
-use std::path::PathBuf;
-use std::sync::OnceLock;
+use std::ffi::OsString;
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use std::{env, fs};
 
-use crate::loaderror::LoadResult;
-use serenity::model::channel::Message;
+use crate::loaderror::{tool_failed, LoadError, LoadResult};
+use serde::Deserialize;
+use serenity::async_trait;
+use tokio::process::Command;
+use url::Url;
 
+pub mod cache;
+pub mod compress;
+mod json_file;
 pub mod loaderror;
+pub mod phash;
 pub mod reddit;
+pub mod retry;
 pub mod tiktok;
 pub mod tumblr;
 pub mod youtube;
+mod ytdlp;
 
 static TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+/// Upload limit of a guild without boosts, guilds with boost tier 2 or 3 allow larger files
 pub const DISCORD_MAX_FILE_SIZE_MB: u16 = 8;
+pub const DISCORD_MAX_ATTACHMENTS: usize = 10;
+/// Some platforms answer with a captcha or a login page when they see a non browser user agent
+const USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 \
+                          (KHTML, like Gecko) Chrome/108.0.0.0 Safari/537.36";
 
-pub enum UrlKind {
-    Reddit(String),
-    Youtube(String),
+/// What the caller wants from a download besides the url. Downloaders ignore the options their
+/// platform does not support, images for example have no quality or time range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadOptions {
+    /// Largest file in MB the caller is able to upload
+    pub max_filesize: u16,
+    /// Highest video resolution like the 720 of 720p. That is the shorter side of the video, so
+    /// portrait videos are compared by their width. `None` takes the best one that fits.
+    pub max_resolution: Option<u32>,
+    /// Only the sound of a video in this format
+    pub audio: Option<AudioFormat>,
+    /// Only the part of the video from `start` until `end`
+    pub start: Option<Duration>,
+    pub end: Option<Duration>,
+    /// Told how far the download is, only downloaders that run yt-dlp know that
+    pub progress: Option<Progress>,
+    /// The caller compresses videos that are too large itself. When no video fits into
+    /// `max_filesize` the smallest one is loaded instead of failing with
+    /// [TooLarge](LoadError::TooLarge).
+    pub compress: bool,
+    /// Told what the user should know about a download that worked anyway
+    pub notes: Option<Notes>,
 }
 
-impl UrlKind {
-    pub async fn load(&self, msg: &Message, max_filesize: u16) -> LoadResult<PathBuf> {
+impl LoadOptions {
+    pub fn new(max_filesize: u16) -> Self {
+        LoadOptions {
+            max_filesize,
+            max_resolution: None,
+            audio: None,
+            start: None,
+            end: None,
+            progress: None,
+            compress: false,
+            notes: None,
+        }
+    }
+
+    /// Whether only a part of the video is wanted
+    pub fn is_clip(&self) -> bool {
+        self.start.is_some() || self.end.is_some()
+    }
+}
+
+/// Called with the percentage of a running download
+#[derive(Clone)]
+pub struct Progress(Arc<dyn Fn(u8) + Send + Sync>);
+
+impl Progress {
+    pub fn new(report: impl Fn(u8) + Send + Sync + 'static) -> Self {
+        Progress(Arc::new(report))
+    }
+
+    pub(crate) fn report(&self, percent: u8) {
+        (self.0)(percent)
+    }
+}
+
+impl Debug for Progress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Options are the same when they report to the same place
+impl PartialEq for Progress {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Collects what the user should know about a download: compromises like a video that only fit
+/// without sound, and the files of a post that failed while the others could be loaded.
+/// Downloaders only leave out failed files when the caller gave them notes.
+#[derive(Clone, Default)]
+pub struct Notes(Arc<Mutex<(Vec<String>, Vec<LoadError>)>>);
+
+impl Notes {
+    pub(crate) fn add(&self, note: impl Into<String>) {
+        self.0.lock().unwrap().0.push(note.into());
+    }
+
+    pub(crate) fn failed(&self, err: LoadError) {
+        self.0.lock().unwrap().1.push(err);
+    }
+
+    /// The notes and the errors of the files that are missing
+    pub fn take(&self) -> (Vec<String>, Vec<LoadError>) {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Debug for Notes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Notes")
+    }
+}
+
+/// Options are the same when they report to the same place
+impl PartialEq for Notes {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Format of audio only downloads, the title and the thumbnail of the video are embedded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    Opus,
+    M4a,
+    /// Every player, Discord included, plays mp3
+    #[default]
+    Mp3,
+}
+
+impl AudioFormat {
+    /// Also the name yt-dlp uses for `--audio-format`
+    pub fn extension(self) -> &'static str {
         match self {
-            UrlKind::Reddit(url) => reddit::load(url, msg, max_filesize).await,
-            UrlKind::Youtube(url) => youtube::load(url, msg, max_filesize).await,
+            AudioFormat::Opus => "opus",
+            AudioFormat::M4a => "m4a",
+            AudioFormat::Mp3 => "mp3",
+        }
+    }
+
+    fn ffmpeg_codec(self) -> &'static str {
+        match self {
+            AudioFormat::Opus => "libopus",
+            AudioFormat::M4a => "aac",
+            AudioFormat::Mp3 => "libmp3lame",
         }
     }
 }
 
-///Converts the given megabyte value to bytes
-fn mbyte_to_byte(mbyte: u64) -> u64 {
+/// A platform the bot can download media from. New platforms only have to implement this trait
+/// and get registered in the [Registry], the handler does not know about any specific platform.
+#[async_trait]
+pub trait Downloader: Send + Sync {
+    /// Name of the platform, it is also the key used to turn the downloader on and off in the
+    /// `[downloaders]` table of the config
+    fn name(&self) -> &'static str;
+
+    /// Whether this downloader knows how to handle the given url
+    fn matches(&self, url: &Url) -> bool;
+
+    /// Id of the post or video on the platform, the same media posted with a different link
+    /// has the same id. `None` when it can not be worked out without a request.
+    fn media_id(&self, _url: &Url) -> Option<String> {
+        None
+    }
+
+    /// Downloads all media behind the url into the working dir and returns the paths of the
+    /// files, the caller is responsible for deleting them after they were uploaded
+    async fn load(&self, url: &Url, options: &LoadOptions) -> LoadResult<Vec<PathBuf>>;
+}
+
+/// Holds every known [Downloader] and picks the one responsible for an url
+#[derive(Default)]
+pub struct Registry {
+    downloaders: Vec<Box<dyn Downloader>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry with every downloader this crate ships with
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register(reddit::Reddit::default())
+            .register(youtube::Youtube)
+            .register(tiktok::TikTok)
+            .register(tumblr::Tumblr::default());
+        registry
+    }
+
+    pub fn register(&mut self, downloader: impl Downloader + 'static) -> &mut Self {
+        self.downloaders.push(Box::new(downloader));
+        self
+    }
+
+    /// Returns the first registered downloader that matches the url
+    pub fn find(&self, url: &Url) -> Option<&dyn Downloader> {
+        self.downloaders
+            .iter()
+            .find(|downloader| downloader.matches(url))
+            .map(Box::as_ref)
+    }
+}
+
+/// Checks if the host of the url is the given domain or one of its subdomains
+fn host_matches(url: &Url, domain: &str) -> bool {
+    match url.host_str() {
+        Some(host) => {
+            host == domain
+                || host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.ends_with('.'))
+        }
+        None => false,
+    }
+}
+
+/// Parses a point in time written as `ss`, `mm:ss` or `hh:mm:ss`
+pub fn parse_timestamp(text: &str) -> Option<Duration> {
+    let parts = text.trim().split(':').collect::<Vec<&str>>();
+    if parts.is_empty() || parts.len() > 3 {
+        return None;
+    }
+
+    let mut seconds: u64 = 0;
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let value = part.parse::<u64>().ok()?;
+        //Only the leading part may be larger than a minute, 1:75 is most likely a typo
+        if i > 0 && value >= 60 {
+            return None;
+        }
+        seconds = seconds.checked_mul(60)?.checked_add(value)?;
+    }
+    Some(Duration::from_secs(seconds))
+}
+
+/// Converts the given megabyte value to bytes. A MB is 1000 * 1000 bytes everywhere, in the size
+/// checks of the loaders, of the handler and in the messages for the user.
+pub fn mbyte_to_byte(mbyte: u64) -> u64 {
     (mbyte * 1000) * 1000
 }
 
+/// Converts bytes to whole megabytes, see [mbyte_to_byte]
+pub fn byte_to_mbyte(bytes: u64) -> u64 {
+    bytes / 1000 / 1000
+}
+
+/// The external programs we run are looked up on the PATH. An env var like
+/// `SOCIAL_LOADERS_YT_DLP` or `SOCIAL_LOADERS_FFMPEG` points to another executable instead, the
+/// tests use that to run stub scripts.
+pub fn tool(name: &str) -> OsString {
+    let var = format!("SOCIAL_LOADERS_{}", name.to_uppercase().replace('-', "_"));
+    env::var_os(var).unwrap_or_else(|| name.into())
+}
+
+/// Because gifs are so huge we convert them to an mp4 file next to the original one. The gif is
+/// removed afterwards, also when the conversion failed.
+pub(crate) async fn convert_gif_to_mp4(path: PathBuf) -> LoadResult<PathBuf> {
+    let new_path = path.with_extension("mp4");
+
+    let output = Command::new(tool("ffmpeg"))
+        .args(["-v", "error", "-y", "-i"])
+        .arg(&path)
+        .arg(&new_path)
+        .current_dir(TEMP_DIR.get_or_try_init(create_working_dir)?)
+        .kill_on_drop(true)
+        .output()
+        .await;
+    let _ = fs::remove_file(&path);
+
+    let output = output?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&new_path);
+        return Err(tool_failed("ffmpeg", &output));
+    }
+    Ok(new_path)
+}
+
+/// Converts the sound of `input` into an audio file next to it with the title and cover picture
+/// embedded. Ogg files can not hold a cover picture with ffmpeg, so opus only gets the title.
+pub(crate) async fn convert_audio(
+    input: &Path,
+    format: AudioFormat,
+    title: Option<&str>,
+    cover: Option<&Path>,
+) -> LoadResult<PathBuf> {
+    let output_path = input.with_extension(format.extension());
+
+    let mut command = Command::new(tool("ffmpeg"));
+    command.args(["-v", "error", "-y", "-i"]).arg(input);
+    if let Some(cover) = cover.filter(|_| format != AudioFormat::Opus) {
+        command.arg("-i").arg(cover).args([
+            "-map",
+            "1:v",
+            "-c:v",
+            "copy",
+            "-disposition:v",
+            "attached_pic",
+        ]);
+    }
+    command.args(["-map", "0:a", "-c:a", format.ffmpeg_codec()]);
+    if format == AudioFormat::Mp3 {
+        //Windows and a lot of players only read version 3 of the tags
+        command.args(["-id3v2_version", "3"]);
+    }
+    if let Some(title) = title {
+        command.arg("-metadata").arg(format!("title={title}"));
+    }
+
+    let output = command
+        .arg(&output_path)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&output_path);
+        return Err(tool_failed("ffmpeg", &output));
+    }
+    Ok(output_path)
+}
+
 fn create_working_dir() -> Result<PathBuf, std::io::Error> {
     let mut working_dir = temp_dir();
     working_dir.push("gamersbot_stuff");
@@ -46,3 +357,52 @@ fn create_working_dir() -> Result<PathBuf, std::io::Error> {
     }
     Ok(working_dir)
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use url::Url;
+
+    use crate::{parse_timestamp, Registry};
+
+    fn find(url: &str) -> Option<&'static str> {
+        let registry = Registry::with_builtin();
+        let url = Url::parse(url).unwrap();
+        registry.find(&url).map(|downloader| downloader.name())
+    }
+
+    #[test]
+    fn test_registry_picks_downloader_by_host() {
+        assert_eq!(
+            find("https://www.reddit.com/r/memes/comments/abc/title/"),
+            Some("reddit")
+        );
+        assert_eq!(
+            find("https://old.reddit.com/r/memes/comments/abc/"),
+            Some("reddit")
+        );
+        assert_eq!(find("https://youtu.be/UT5F9AXjwhg"), Some("youtube"));
+        assert_eq!(
+            find("https://m.youtube.com/watch?v=TK4N5W22Gts"),
+            Some("youtube")
+        );
+        assert_eq!(find("https://www.tiktok.com/@user/video/1"), Some("tiktok"));
+        assert_eq!(find("https://staff.tumblr.com/post/1"), Some("tumblr"));
+        assert_eq!(find("https://notreddit.com/r/memes"), None);
+        assert_eq!(find("https://example.com/youtube"), None);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("42"), Some(Duration::from_secs(42)));
+        assert_eq!(parse_timestamp("1:23"), Some(Duration::from_secs(83)));
+        assert_eq!(parse_timestamp("01:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_timestamp("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_timestamp("1:75"), None);
+        assert_eq!(parse_timestamp("1:2:3:4"), None);
+        assert_eq!(parse_timestamp("1m"), None);
+        assert_eq!(parse_timestamp("999999999999999999:0"), None);
+        assert_eq!(parse_timestamp(""), None);
+    }
+}
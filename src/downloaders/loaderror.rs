@@ -1,57 +1,319 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::path::StripPrefixError;
+use std::process::Output;
+use std::time::Duration;
+
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Response, StatusCode};
+
+use crate::{byte_to_mbyte, ytdlp};
 
 pub type LoadResult<T> = Result<T, LoadError>;
 
+/// Why a url could not be loaded. Every variant has a stable [code](LoadError::code) for logs and
+/// config, a [message](LoadError::user_message) for the user and the operator detail in its
+/// [Display] output.
+///
 /// Ignore : Thanks to reddit´s stupid api design they store the post url in the same attribute as the images so have this variant
 ///         to signal that we do not want to send the file that was created to discord
-/// Rejected: We have a valid image or video but it cant be sent do discord foe i.E. filesize restrictions
 #[derive(Debug)]
 pub enum LoadError {
     Ignore(String),
-    Rejected(String),
-    Error(Box<dyn Error + Send + Sync>),
+    /// Sizes in bytes, `size` is unknown when nothing was downloaded
+    TooLarge {
+        size: Option<u64>,
+        limit: u64,
+    },
+    /// The platform has it but we can not post it, the reason is shown to the user
+    Unsupported {
+        platform: &'static str,
+        reason: String,
+    },
+    NotFound,
+    /// Only visible to some users or after a login
+    Private,
+    AgeRestricted,
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// An external program like yt-dlp or ffmpeg exited with an error
+    ToolFailed {
+        tool: &'static str,
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    Network(reqwest::Error),
+    /// Bugs and answers we do not understand, nothing the user can do about it
+    Internal(Box<dyn Error + Send + Sync>),
+}
+
+impl LoadError {
+    /// Every [code](LoadError::code), the keys of the `[messages]` table of the config
+    pub const CODES: [&'static str; 10] = [
+        "ignored",
+        "too_large",
+        "unsupported",
+        "not_found",
+        "private",
+        "age_restricted",
+        "rate_limited",
+        "tool_failed",
+        "network",
+        "internal",
+    ];
+
+    /// Identifies the kind of error, it does not change between versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            LoadError::Ignore(_) => "ignored",
+            LoadError::TooLarge { .. } => "too_large",
+            LoadError::Unsupported { .. } => "unsupported",
+            LoadError::NotFound => "not_found",
+            LoadError::Private => "private",
+            LoadError::AgeRestricted => "age_restricted",
+            LoadError::RateLimited { .. } => "rate_limited",
+            LoadError::ToolFailed { .. } => "tool_failed",
+            LoadError::Network(_) => "network",
+            LoadError::Internal(_) => "internal",
+        }
+    }
+
+    /// Whether the user caused it or can do something about it, the others are our problem
+    pub fn is_user_facing(&self) -> bool {
+        !matches!(
+            self,
+            LoadError::ToolFailed { .. } | LoadError::Network(_) | LoadError::Internal(_)
+        )
+    }
+
+    /// Whether trying again a bit later might work, for hiccups of the platform or the connection
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LoadError::RateLimited { .. } => true,
+            LoadError::Network(err) => match err.status() {
+                Some(status) => status.is_server_error(),
+                None => err.is_timeout() || err.is_connect() || err.is_request() || err.is_body(),
+            },
+            LoadError::ToolFailed {
+                tool: "yt-dlp",
+                stderr,
+                ..
+            } => ytdlp::is_hiccup(stderr),
+            _ => false,
+        }
+    }
+
+    /// The message for the user in english, see [render](LoadError::render) for other languages
+    pub fn user_message(&self) -> String {
+        self.render(self.default_template())
+    }
+
+    fn default_template(&self) -> &'static str {
+        match self {
+            LoadError::Ignore(_) => "{reason}",
+            LoadError::TooLarge { .. } => {
+                "The file is {size}MB large, the limit here is {limit}MB. Post only a part of a \
+                 video like `<link> 1:23-1:45` or download it manually"
+            }
+            LoadError::Unsupported { .. } => "{reason}",
+            LoadError::NotFound => "That post does not exist or was deleted",
+            LoadError::Private => "That post is private, i can not see it",
+            LoadError::AgeRestricted => "That video is age restricted, i can not download it",
+            LoadError::RateLimited { .. } => {
+                "The site does not let me download more right now, try again {retry_after}"
+            }
+            LoadError::ToolFailed { .. } | LoadError::Network(_) | LoadError::Internal(_) => {
+                "Something went wrong while downloading, try again later"
+            }
+        }
+    }
+
+    /// Fills the placeholders `{size}`, `{limit}`, `{platform}`, `{reason}` and `{retry_after}`
+    /// of a message from the config with the values of this error
+    pub fn render(&self, template: &str) -> String {
+        let (mut size, mut limit, mut platform, mut reason) = (None, None, "", "");
+        let mut retry_after = "later".to_string();
+        match self {
+            LoadError::Ignore(text) => reason = text,
+            LoadError::TooLarge {
+                size: bytes,
+                limit: limit_bytes,
+            } => {
+                size = bytes.map(byte_to_mbyte);
+                limit = Some(byte_to_mbyte(*limit_bytes));
+            }
+            LoadError::Unsupported {
+                platform: name,
+                reason: text,
+            } => (platform, reason) = (name, text),
+            LoadError::RateLimited {
+                retry_after: Some(wait),
+            } => retry_after = format!("in {} seconds", wait.as_secs().max(1)),
+            _ => {}
+        }
+
+        template
+            .replace(
+                "{size}",
+                &size.map_or("more than the limit".to_string(), |mb| mb.to_string()),
+            )
+            .replace("{limit}", &limit.unwrap_or_default().to_string())
+            .replace("{platform}", platform)
+            .replace("{reason}", reason)
+            .replace("{retry_after}", &retry_after)
+    }
 }
 
 impl Error for LoadError {}
 
+/// The operator detail, this is what ends up in the logs
 impl Display for LoadError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             LoadError::Ignore(reason) => write!(f, "{reason}"),
-            LoadError::Rejected(reason) => write!(f, "{reason}"),
-            LoadError::Error(e) => write!(f, "{e}"),
+            LoadError::TooLarge {
+                size: Some(size),
+                limit,
+            } => write!(f, "File of {size} bytes is over the limit of {limit} bytes"),
+            LoadError::TooLarge { size: None, limit } => {
+                write!(f, "No file fits into the limit of {limit} bytes")
+            }
+            LoadError::Unsupported { platform, reason } => {
+                write!(f, "Not supported by {platform}: {reason}")
+            }
+            LoadError::NotFound => write!(f, "The media does not exist"),
+            LoadError::Private => write!(f, "The media is private or needs a login"),
+            LoadError::AgeRestricted => write!(f, "The media is age restricted"),
+            LoadError::RateLimited {
+                retry_after: Some(wait),
+            } => write!(f, "Rate limited, retry after {} seconds", wait.as_secs()),
+            LoadError::RateLimited { retry_after: None } => write!(f, "Rate limited"),
+            LoadError::ToolFailed {
+                tool,
+                exit_code,
+                stderr,
+            } => match exit_code {
+                Some(code) => write!(f, "{tool} failed with exit code {code}: {stderr}"),
+                None => write!(f, "{tool} was killed: {stderr}"),
+            },
+            LoadError::Network(e) => write!(f, "Network error: {e}"),
+            LoadError::Internal(e) => write!(f, "{e}"),
         }
     }
 }
 
-impl From<String> for LoadError {
-    fn from(value: String) -> Self {
-        LoadError::Rejected(value)
+/// A [ToolFailed](LoadError::ToolFailed) from what the program wrote to stderr
+pub(crate) fn tool_failed(tool: &'static str, output: &Output) -> LoadError {
+    LoadError::ToolFailed {
+        tool,
+        exit_code: output.status.code(),
+        stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
     }
 }
 
-impl From<&str> for LoadError {
-    fn from(value: &str) -> Self {
-        value.to_string().into()
+/// Like [Response::error_for_status] but the error is the matching variant, the `Retry-After`
+/// header of a 429 is kept
+pub(crate) trait CheckStatus: Sized {
+    fn check_status(self) -> LoadResult<Self>;
+}
+
+impl CheckStatus for Response {
+    fn check_status(self) -> LoadResult<Self> {
+        if self.status() != StatusCode::TOO_MANY_REQUESTS {
+            return self.error_for_status().map_err(LoadError::from);
+        }
+        Err(LoadError::RateLimited {
+            retry_after: self
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs),
+        })
     }
 }
 
 impl From<std::io::Error> for LoadError {
     fn from(value: std::io::Error) -> Self {
-        LoadError::Error(Box::new(value))
+        LoadError::Internal(Box::new(value))
     }
 }
 
 impl From<reqwest::Error> for LoadError {
     fn from(value: reqwest::Error) -> Self {
-        LoadError::Error(Box::new(value))
+        match value.status() {
+            Some(StatusCode::NOT_FOUND | StatusCode::GONE) => LoadError::NotFound,
+            Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => LoadError::Private,
+            Some(StatusCode::TOO_MANY_REQUESTS) => LoadError::RateLimited { retry_after: None },
+            _ => LoadError::Network(value),
+        }
     }
 }
 
 impl From<StripPrefixError> for LoadError {
     fn from(value: StripPrefixError) -> Self {
-        LoadError::Error(Box::new(value))
+        LoadError::Internal(Box::new(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::loaderror::LoadError;
+
+    #[test]
+    fn test_user_messages() {
+        let too_large = LoadError::TooLarge {
+            size: Some(31_000_000),
+            limit: 25_000_000,
+        };
+        assert_eq!(too_large.code(), "too_large");
+        assert!(too_large
+            .user_message()
+            .starts_with("The file is 31MB large, the limit here is 25MB"));
+        assert_eq!(
+            too_large.render("Die Datei hat {size}MB, erlaubt sind {limit}MB"),
+            "Die Datei hat 31MB, erlaubt sind 25MB"
+        );
+
+        let rate_limited = LoadError::RateLimited {
+            retry_after: Some(Duration::from_secs(30)),
+        };
+        assert!(rate_limited
+            .user_message()
+            .ends_with("try again in 30 seconds"));
+
+        let failed = LoadError::ToolFailed {
+            tool: "yt-dlp",
+            exit_code: Some(1),
+            stderr: "ERROR: something".into(),
+        };
+        assert!(!failed.is_user_facing());
+        assert!(!failed.user_message().contains("ERROR"));
+        assert_eq!(
+            failed.to_string(),
+            "yt-dlp failed with exit code 1: ERROR: something"
+        );
+    }
+
+    #[test]
+    fn test_codes_are_listed() {
+        let errors = [
+            LoadError::Ignore(String::new()),
+            LoadError::TooLarge {
+                size: None,
+                limit: 1,
+            },
+            LoadError::NotFound,
+            LoadError::Private,
+            LoadError::AgeRestricted,
+            LoadError::RateLimited { retry_after: None },
+            LoadError::Internal("bug".into()),
+        ];
+        for error in errors {
+            assert!(LoadError::CODES.contains(&error.code()));
+        }
     }
 }
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::compress::is_video;
+use crate::json_file::JsonFile;
+use crate::tool;
+
+/// A dHash compares every pixel of a 9x8 grayscale thumbnail with its right neighbour
+const HASH_WIDTH: u32 = 9;
+const HASH_HEIGHT: u32 = 8;
+/// Keyframes of a video we hash, enough to tell clips apart without decoding the whole file
+const MAX_KEYFRAMES: usize = 8;
+/// Black frames, white frames and solid colors all hash to (almost) the same value, they would
+/// make every video with a fade in look like a repost
+const MIN_HASH_BITS: u32 = 4;
+
+/// Perceptual hash of a single image, similar images have hashes with a small [distance]
+pub fn dhash(pixels: &[u8]) -> u64 {
+    let mut hash = 0u64;
+    for y in 0..HASH_HEIGHT as usize {
+        for x in 0..(HASH_WIDTH - 1) as usize {
+            let left = pixels[y * HASH_WIDTH as usize + x];
+            let right = pixels[y * HASH_WIDTH as usize + x + 1];
+            hash = (hash << 1) | u64::from(left < right);
+        }
+    }
+    hash
+}
+
+/// Number of bits two hashes differ in
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Hashes an image, or up to [MAX_KEYFRAMES] keyframes of a video. Files we can not decode and
+/// frames without any detail give no hashes.
+pub async fn hash_file(path: &Path) -> Vec<u64> {
+    let hashes = match is_video(path) {
+        true => video_hashes(path).await,
+        false => {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || image_hash(&path))
+                .await
+                .ok()
+                .flatten()
+                .into_iter()
+                .collect()
+        }
+    };
+    hashes
+        .into_iter()
+        .filter(|hash| (MIN_HASH_BITS..=64 - MIN_HASH_BITS).contains(&hash.count_ones()))
+        .collect()
+}
+
+fn image_hash(path: &Path) -> Option<u64> {
+    let thumbnail = image::open(path)
+        .map_err(|err| warn!("Could not decode {} for hashing: {err}", path.display()))
+        .ok()?
+        .resize_exact(HASH_WIDTH, HASH_HEIGHT, FilterType::Triangle)
+        .to_luma8();
+    Some(dhash(thumbnail.as_raw()))
+}
+
+/// Lets ffmpeg decode only the keyframes and scale them down to the hash size, the raw gray
+/// pixels of every frame are written to stdout one after another
+async fn video_hashes(path: &Path) -> Vec<u64> {
+    let output = Command::new(tool("ffmpeg"))
+        .args(["-v", "error", "-skip_frame", "nokey", "-i"])
+        .arg(path)
+        .args([
+            "-vf",
+            &format!("scale={HASH_WIDTH}:{HASH_HEIGHT},format=gray"),
+        ])
+        .args(["-vsync", "0", "-frames:v", &MAX_KEYFRAMES.to_string()])
+        .args(["-f", "rawvideo", "-"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => output
+            .stdout
+            .chunks_exact((HASH_WIDTH * HASH_HEIGHT) as usize)
+            .map(dhash)
+            .collect(),
+        Ok(output) => {
+            warn!(
+                "ffmpeg could not extract keyframes of {}: {}",
+                path.display(),
+                output.status
+            );
+            Vec::new()
+        }
+        Err(err) => {
+            warn!("Could not run ffmpeg for {}: {err}", path.display());
+            Vec::new()
+        }
+    }
+}
+
+/// The hashes of everything we posted per guild together with a link to the post, kept in a
+/// json file so reposts of last week are still found after a restart
+pub struct RepostStore {
+    file: JsonFile,
+    /// Most bits two hashes may differ in to count as the same image
+    threshold: u32,
+    max_per_guild: usize,
+    guilds: Mutex<HashMap<u64, Vec<Record>>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Record {
+    hashes: Vec<u64>,
+    /// Jump link to the message with the original
+    link: String,
+}
+
+impl RepostStore {
+    /// Loads the hashes from `path`, a missing or broken file gives an empty store
+    pub fn open(path: &Path, threshold: u32, max_per_guild: usize) -> RepostStore {
+        let file = JsonFile::new(path, "repost");
+        let guilds = file.read();
+        info!("Loaded repost hashes from {}", path.display());
+
+        RepostStore {
+            file,
+            threshold,
+            max_per_guild,
+            guilds: Mutex::new(guilds),
+        }
+    }
+
+    /// Link to the earlier post with the media of one file, that is when at least half of its
+    /// hashes have a close match in the post. Every file of a post is looked up on its own.
+    pub fn find(&self, guild_id: u64, hashes: &[u64]) -> Option<String> {
+        if hashes.is_empty() {
+            return None;
+        }
+        let guilds = self.guilds.lock().unwrap();
+        guilds.get(&guild_id)?.iter().rev().find_map(|record| {
+            let matching = hashes
+                .iter()
+                .filter(|hash| {
+                    record
+                        .hashes
+                        .iter()
+                        .any(|other| distance(**hash, *other) <= self.threshold)
+                })
+                .count();
+            (matching * 2 >= hashes.len()).then(|| record.link.clone())
+        })
+    }
+
+    /// Remembers the hashes of a post, the oldest posts of the guild are dropped once there are
+    /// more than `max_per_guild`
+    pub fn insert(&self, guild_id: u64, hashes: Vec<u64>, link: String) {
+        if hashes.is_empty() {
+            return;
+        }
+        let mut guilds = self.guilds.lock().unwrap();
+        let records = guilds.entry(guild_id).or_default();
+        records.push(Record { hashes, link });
+        if records.len() > self.max_per_guild {
+            let surplus = records.len() - self.max_per_guild;
+            records.drain(..surplus);
+        }
+        self.file.write(&*guilds);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::{GrayImage, Luma};
+
+    use crate::phash::{dhash, distance, hash_file, RepostStore};
+
+    /// Diagonal stripes, `shift` moves them and `brightness` is added to every pixel
+    fn stripes(shift: u32, brightness: u8) -> GrayImage {
+        GrayImage::from_fn(90, 80, |x, y| {
+            let value = ((x + y + shift) / 15 % 2) as u8 * 150 + 50;
+            Luma([value.saturating_add(brightness)])
+        })
+    }
+
+    async fn hash(image: &GrayImage, name: &str) -> Vec<u64> {
+        let path = std::env::temp_dir().join(format!("social_loaders_phash_{name}.png"));
+        image.save(&path).unwrap();
+        let hashes = hash_file(&path).await;
+        let _ = std::fs::remove_file(&path);
+        hashes
+    }
+
+    #[test]
+    fn test_dhash_of_gradient() {
+        let rising = (0..72).map(|i| (i % 9) as u8 * 10).collect::<Vec<u8>>();
+        assert_eq!(dhash(&rising), u64::MAX);
+
+        let flat = vec![128; 72];
+        assert_eq!(dhash(&flat), 0);
+        assert_eq!(distance(dhash(&rising), dhash(&flat)), 64);
+    }
+
+    #[tokio::test]
+    async fn test_similar_images_have_close_hashes() {
+        let original = hash(&stripes(0, 0), "original").await;
+        let brighter = hash(&stripes(0, 30), "brighter").await;
+        let different = hash(&stripes(22, 0), "different").await;
+
+        assert_eq!(original.len(), 1);
+        assert!(distance(original[0], brighter[0]) <= 4);
+        assert!(distance(original[0], different[0]) > 10);
+    }
+
+    #[tokio::test]
+    async fn test_flat_images_are_not_hashed() {
+        assert!(hash(&GrayImage::new(90, 80), "black").await.is_empty());
+    }
+
+    #[test]
+    fn test_store_finds_reposts_per_guild() {
+        let path = std::env::temp_dir().join("social_loaders_reposts.json");
+        let _ = std::fs::remove_file(&path);
+        let store = RepostStore::open(&path, 10, 2);
+
+        store.insert(1, vec![0xF0F0_F0F0_F0F0_F0F0], "first".into());
+        assert_eq!(
+            store.find(1, &[0xF0F0_F0F0_F0F0_F0F1]),
+            Some("first".into())
+        );
+        assert_eq!(store.find(2, &[0xF0F0_F0F0_F0F0_F0F0]), None);
+        assert_eq!(store.find(1, &[0x0F0F_0F0F_0F0F_0F0F]), None);
+
+        //Videos match when at least half of their keyframes match
+        store.insert(1, vec![0xAAAA, 0xBBBB_0000], "video".into());
+        assert_eq!(
+            store.find(1, &[0xAAAA, 0xFFFF_FFFF_0000]),
+            Some("video".into())
+        );
+
+        //A single file of a post is enough
+        assert_eq!(store.find(1, &[0xBBBB_0000]), Some("video".into()));
+
+        //Only the newest two posts are kept, the store survives a restart
+        store.insert(1, vec![0x1234_5678], "third".into());
+        let reopened = RepostStore::open(&path, 10, 2);
+        assert_eq!(reopened.find(1, &[0xF0F0_F0F0_F0F0_F0F0]), None);
+        assert_eq!(reopened.find(1, &[0x1234_5678]), Some("third".into()));
+        let _ = std::fs::remove_file(&path);
+    }
+}
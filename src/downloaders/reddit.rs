@@ -2,300 +2,516 @@ use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::ops::Add;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use image::io::Reader as ImageReader;
 use image::ImageFormat;
 use reqwest::Client;
-use serenity::model::channel::Message;
+use serenity::async_trait;
 use tokio::process::Command;
 use tracing::info;
+use url::Url;
 use uuid::Uuid;
 
-use crate::loaderror::{LoadError, LoadResult};
-use crate::reddit::RedditFileUrl::{Image, Video};
-use crate::{create_working_dir, mbyte_to_byte, TEMP_DIR};
-
-enum RedditFileUrl {
-    Image(String),
-    Video(String),
+use crate::loaderror::{tool_failed, CheckStatus, LoadError, LoadResult};
+use crate::reddit::dash::Kind;
+use crate::reddit::model::RedditFileUrl::{Gallery, Image, Video};
+use crate::reddit::model::{PostData, RedditVideo};
+use crate::{
+    convert_audio, convert_gif_to_mp4, create_working_dir, host_matches, mbyte_to_byte, tool,
+    AudioFormat, Downloader, LoadOptions, TEMP_DIR, USER_AGENT,
+};
+
+mod dash;
+mod model;
+
+pub struct Reddit {
+    client: Client,
+    /// The json of a post is requested from `<base_url>/comments/<id>/.json`
+    base_url: Url,
+    /// The images of a gallery are requested from `<media_url>/<media id>.<extension>`
+    media_url: Url,
 }
 
-pub async fn load(url: &str, _msg: &Message, max_filesize: u16) -> LoadResult<PathBuf> {
-    let client = Client::new();
-
-    let json_url = {
-        if url.ends_with('/') {
-            format!("{}{}", &url[0..url.len() - 2], ".json")
-        } else if url.contains('?') {
-            let split = url.split('?').next().unwrap();
-            format!("{}{}", split, "/.json")
-        } else {
-            format!("{}{}", &url[0..url.len() - 2], "/.json")
+impl Reddit {
+    /// Tests use this to send the requests to a local server
+    pub fn new(client: Client, base_url: Url) -> Self {
+        Reddit {
+            client,
+            base_url,
+            media_url: Url::parse("https://i.redd.it/").expect("Valid reddit media url"),
         }
-    };
+    }
+
+    /// Tests use this to load the images of galleries from a local server as well
+    pub fn with_media_url(mut self, media_url: Url) -> Self {
+        self.media_url = media_url;
+        self
+    }
+}
+
+impl Default for Reddit {
+    fn default() -> Self {
+        Reddit::new(
+            Client::new(),
+            Url::parse("https://www.reddit.com/").expect("Valid reddit url"),
+        )
+    }
+}
+
+#[async_trait]
+impl Downloader for Reddit {
+    fn name(&self) -> &'static str {
+        "reddit"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        host_matches(url, "reddit.com") || host_matches(url, "redd.it")
+    }
+
+    fn media_id(&self, url: &Url) -> Option<String> {
+        post_id(url)
+    }
+
+    async fn load(&self, url: &Url, options: &LoadOptions) -> LoadResult<Vec<PathBuf>> {
+        load(&self.client, &self.base_url, &self.media_url, url, options).await
+    }
+}
+
+pub async fn load(
+    client: &Client,
+    base_url: &Url,
+    media_url: &Url,
+    url: &Url,
+    options: &LoadOptions,
+) -> LoadResult<Vec<PathBuf>> {
+    let post_id = resolve_post_id(client, url).await?;
+    //raw_json stops reddit from html escaping the urls in the response
+    let json_url = base_url
+        .join(&format!("comments/{post_id}/.json?raw_json=1"))
+        .map_err(|err| LoadError::Internal(err.into()))?;
 
     let res = client
         .get(json_url)
-        .header(
-            "user-agent",
-            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like \
-             Gecko) Chrome/108.0.0.0 Safari/537.36",
-        )
+        .header("user-agent", USER_AGENT)
         .send()
         .await?
-        .json::<serde_json::Value>()
+        .check_status()?
+        .bytes()
         .await?;
+    let post = model::parse_post(&res)?;
 
     let working_dir = TEMP_DIR.get_or_try_init(create_working_dir)?;
-    let downloaded_file_path = match extract_file_url_from_reddit_response(&res) {
-        Ok(Image(image_url)) => {
-            let url = image_url.clone();
-            let image_file_extension = image_url.split(".").last().unwrap();
-            let image_bytes = client.get(url).send().await?.bytes().await.unwrap();
-
-            let filename = Uuid::new_v4().to_string() + "." + image_file_extension;
-
-            let path = working_dir.join(&filename);
-            File::create(&path)?.write_all(&image_bytes)?;
-
-            //We simply try to open the file as an Image, if it fails we wrote the Bytes of
-            // a text Post to the File
-            let Ok(_) = ImageReader::open(&path)?.decode() else {
-                return Err(LoadError::Ignore("This is a text post".into()));
-            };
-            let Ok(format) = ImageFormat::from_path(&path) else {
-                return Err(LoadError::Ignore("This is a text post".into()));
-            };
-
-            //Because gifs are so fucking huge we convert the gif to an mp4 file
-            let filename = match format {
-                ImageFormat::Gif => {
-                    return convert_gif_to_mp4(path).await;
+    let downloaded_file_paths = match post.file_url() {
+        Ok(Gallery(file_names)) => {
+            let mut paths = Vec::with_capacity(file_names.len());
+            for file_name in file_names {
+                let image_url = media_url
+                    .join(&file_name)
+                    .map_err(|err| LoadError::Internal(err.into()))?;
+                match download_image(client, image_url.as_str(), working_dir).await {
+                    Ok(path) => paths.push(path),
+                    Err(err) => {
+                        paths.iter().for_each(|path| {
+                            let _ = fs::remove_file(path);
+                        });
+                        return Err(err);
+                    }
                 }
-                _ => filename,
-            };
-
-            PathBuf::from(filename)
-        }
-        Ok(Video(vid_url)) => {
-            //Get Video and Audio Url
-            let mut audio_url = vid_url.split_inclusive('/').collect::<Vec<&str>>();
-            let position = audio_url.len() - 1;
-            audio_url[position] = "DASH_audio.mp4?source=fallback";
-
-            let audio_url = audio_url
-                .iter()
-                .fold("".to_string(), |current, &next| current + next);
-
-            //Download both files
-            let vid = client.get(vid_url).send().await?;
-
-            //Check the content_length header to determine if we should event download the
-            //file or if it will be too large anyway
-            let size = vid
-                .content_length()
-                .ok_or("Failed to read Content_length Header from Reddit Download")?;
-            info!("The Content_length header is {}", size);
-
-            if size > mbyte_to_byte(max_filesize.into()) {
-                return Err(format!(
-                    "Reddit File is over the Limit of {} Megabytes",
-                    max_filesize
-                )
-                .into());
-            }
-
-            let video = vid.bytes().await.unwrap();
-            let audio = client.get(audio_url).send().await?.bytes().await?;
-
-            let video_path = working_dir.join(Uuid::new_v4().to_string());
-            let audio_path = working_dir.join(Uuid::new_v4().to_string());
-            let mut video_file = File::create(&video_path)?;
-            let mut audio_file = File::create(&audio_path)?;
-            video_file.write_all(video.as_ref())?;
-            audio_file.write_all(audio.as_ref())?;
-
-            //Combine audio and video track using ffmpeg
-            let filename = Uuid::new_v4().to_string().add(".mp4");
-            let mut handle = Command::new("ffmpeg")
-                .args([
-                    "-i",
-                    &Uuid::new_v4().to_string(),
-                    "-i",
-                    &Uuid::new_v4().to_string(),
-                    "-c",
-                    "copy",
-                    filename.as_str(),
-                ])
-                .current_dir(&working_dir)
-                .spawn()?;
-
-            match handle.wait().await {
-                Ok(_) => {}
-                Err(err) => return Err(err.into()),
             }
-
-            fs::remove_file(video_path)?;
-            fs::remove_file(audio_path)?;
-
-            PathBuf::from(filename)
+            paths
         }
+        Ok(Image(image_url)) => vec![download_image(client, &image_url, working_dir).await?],
+        Ok(Video(video)) => match options.audio {
+            Some(format) => vec![
+                download_audio(
+                    client,
+                    &video,
+                    &post,
+                    format,
+                    options.max_filesize,
+                    working_dir,
+                )
+                .await?,
+            ],
+            None => vec![download_video(client, &video, options, working_dir).await?],
+        },
         Err(err) => {
             return Err(err);
         }
     };
-    Ok(working_dir.join(downloaded_file_path))
+    Ok(downloaded_file_paths)
+}
+
+/// Downloads the best video and audio track that fit into the size limit together, or the
+/// smallest ones when the caller compresses them, and combines them with ffmpeg. Silent videos
+/// are used as they are, a video only loses its sound when nothing else fits.
+async fn download_video(
+    client: &Client,
+    video: &RedditVideo,
+    options: &LoadOptions,
+    working_dir: &Path,
+) -> LoadResult<PathBuf> {
+    let renditions = dash::renditions(client, video).await?;
+    let with_audio = video.has_audio != Some(false);
+    let limit = mbyte_to_byte(options.max_filesize.into());
+    let mut selected = dash::select(
+        &renditions,
+        video.duration,
+        with_audio,
+        limit,
+        options.max_resolution,
+    )
+    .or_else(|| {
+        options
+            .compress
+            .then(|| dash::select_smallest(&renditions, video.duration, with_audio))
+            .flatten()
+    });
+    //Without sound is the last resort, a video that gets compressed keeps it
+    if selected.is_none() && with_audio && !options.compress {
+        selected = dash::select(
+            &renditions,
+            video.duration,
+            false,
+            limit,
+            options.max_resolution,
+        );
+        if let (Some(_), Some(notes)) = (selected, &options.notes) {
+            notes.add("The video is only small enough without sound, so it has none");
+        }
+    }
+    let Some((video_track, audio_track)) = selected else {
+        return Err(LoadError::TooLarge {
+            size: dash::smallest_size(&renditions, video.duration, Kind::Video),
+            limit,
+        });
+    };
+    info!(
+        "Selected video track {} and audio track {:?}",
+        video_track.url,
+        audio_track.map(|audio| audio.url.as_str())
+    );
+
+    let filename = Uuid::new_v4().to_string().add(".mp4");
+    let Some(audio_track) = audio_track else {
+        let path = working_dir.join(filename);
+        download_to(client, &video_track.url, &path).await?;
+        return Ok(path);
+    };
+
+    let video_path = working_dir.join(Uuid::new_v4().to_string());
+    let audio_path = working_dir.join(Uuid::new_v4().to_string());
+    let downloads = async {
+        download_to(client, &video_track.url, &video_path).await?;
+        download_to(client, &audio_track.url, &audio_path).await
+    };
+
+    //Combine audio and video track using ffmpeg
+    let merged = match downloads.await {
+        Ok(_) => Command::new(tool("ffmpeg"))
+            .arg("-i")
+            .arg(&video_path)
+            .arg("-i")
+            .arg(&audio_path)
+            .args(["-c", "copy", filename.as_str()])
+            .current_dir(working_dir)
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(LoadError::from),
+        Err(err) => Err(err),
+    };
+
+    let _ = fs::remove_file(video_path);
+    let _ = fs::remove_file(audio_path);
+
+    let output = merged?;
+    if !output.status.success() {
+        return Err(tool_failed("ffmpeg", &output));
+    }
+    Ok(working_dir.join(filename))
 }
 
-async fn convert_gif_to_mp4(path: PathBuf) -> LoadResult<PathBuf> {
-    let new_filename = path
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .split(".")
-        .next()
-        .unwrap();
-    let new_filename = String::from(new_filename) + ".mp4";
-
-    let mut new_path = PathBuf::from(path.parent().unwrap());
-    new_path.push(new_filename);
-
-    let mut handle = Command::new("ffmpeg")
-        .args(["-i", path.to_str().unwrap(), new_path.to_str().unwrap()])
-        .current_dir(TEMP_DIR.get_or_try_init(create_working_dir)?)
-        .spawn()?;
-
-    match handle.wait().await {
-        Ok(_) => {}
-        Err(err) => return Err(err.into()),
+/// Downloads the best audio track that fits into the size limit and converts it, the title and
+/// the thumbnail of the post are embedded
+async fn download_audio(
+    client: &Client,
+    video: &RedditVideo,
+    post: &PostData,
+    format: AudioFormat,
+    max_filesize: u16,
+    working_dir: &Path,
+) -> LoadResult<PathBuf> {
+    let no_sound = || LoadError::Unsupported {
+        platform: "Reddit",
+        reason: "This Reddit video has no sound".into(),
+    };
+    if video.has_audio == Some(false) {
+        return Err(no_sound());
+    }
+    let renditions = dash::renditions(client, video).await?;
+    let limit = mbyte_to_byte(max_filesize.into());
+    let Some(track) = dash::select_audio(&renditions, video.duration, limit) else {
+        return match dash::smallest_size(&renditions, video.duration, Kind::Audio) {
+            Some(size) => Err(LoadError::TooLarge {
+                size: Some(size),
+                limit,
+            }),
+            None => Err(no_sound()),
+        };
+    };
+    info!("Selected audio track {}", track.url);
+
+    let audio_path = working_dir.join(Uuid::new_v4().to_string());
+    download_to(client, &track.url, &audio_path).await?;
+    //The audio is still worth posting without a cover
+    let mut cover_path = None;
+    if let Some(thumbnail) = post.thumbnail_url() {
+        let path = working_dir.join(Uuid::new_v4().to_string().add(".jpg"));
+        match download_to(client, &thumbnail, &path).await {
+            Ok(_) => cover_path = Some(path),
+            Err(err) => info!("Could not download thumbnail {thumbnail}: {err}"),
+        }
     }
 
-    Ok(new_path)
+    let converted = convert_audio(
+        &audio_path,
+        format,
+        post.title.as_deref(),
+        cover_path.as_deref(),
+    )
+    .await;
+    let _ = fs::remove_file(audio_path);
+    if let Some(cover_path) = cover_path {
+        let _ = fs::remove_file(cover_path);
+    }
+    converted
 }
 
-//This is super ugly and i am pretty sure there is a way better method of
-// handling this. The idea is to check for the video url and when none can be
-// found look for the img url
-fn extract_file_url_from_reddit_response(json: &serde_json::Value) -> LoadResult<RedditFileUrl> {
-    let result = extract_video_url(json).unwrap_or(extract_img_url(json)?);
-    Ok(result)
+async fn download_to(client: &Client, url: &Url, path: &Path) -> LoadResult<()> {
+    let bytes = client
+        .get(url.clone())
+        .header("user-agent", USER_AGENT)
+        .send()
+        .await?
+        .check_status()?
+        .bytes()
+        .await?;
+    File::create(path)?.write_all(&bytes)?;
+    Ok(())
 }
 
-/// Web scraping with rust is pure pain
-fn extract_video_url(json: &serde_json::Value) -> LoadResult<RedditFileUrl> {
-    //TODO: error handling if the post has no video
-    let res = json
-        .as_array()
-        .ok_or("Could not load from given url, please provide a valid reddit url")?
-        .get(0)
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("data")
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("children")
-        .unwrap()
-        .as_array()
-        .unwrap()
-        .get(0)
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("data")
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("secure_media")
-        .unwrap()
-        .as_object()
-        .ok_or("This Reddit Link is not a link to a video, mission abort")?
-        .get("reddit_video")
-        .ok_or("This Reddit Link is not a link to a video, mission abort")?
-        .as_object()
-        .unwrap()
-        .get("fallback_url")
-        .unwrap()
-        .as_str()
-        .unwrap()
-        .to_string();
-
-    Ok(Video(res))
+/// Reddit has a lot of urls for the same post (`old.`, `np.`, `m.`, share links, short links,
+/// query strings), the post id is the only thing they have in common. Urls that dont contain the
+/// id, like share links and `v.redd.it` links, redirect to the permalink so we follow them.
+async fn resolve_post_id(client: &Client, url: &Url) -> LoadResult<String> {
+    if let Some(post_id) = post_id(url) {
+        return Ok(post_id);
+    }
+
+    let response = client
+        .get(url.clone())
+        .header("user-agent", USER_AGENT)
+        .send()
+        .await?;
+    info!("Reddit url {url} redirected to {}", response.url());
+
+    post_id(response.url())
+        .ok_or_else(|| LoadError::Ignore(format!("{url} does not link to a reddit post")))
 }
 
-fn _extract_video_name_from_reddit_response(json: &serde_json::Value) -> String {
-    json.as_array()
-        .unwrap()
-        .get(0)
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("data")
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("children")
-        .unwrap()
-        .as_array()
-        .unwrap()
-        .get(0)
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("data")
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("title")
-        .unwrap()
-        .as_str()
-        .unwrap()
-        .to_string()
+/// Returns the post id for permalinks (`/r/<sub>/comments/<id>/<slug>`), galleries
+/// (`/gallery/<id>`) and `redd.it/<id>` short links
+pub(crate) fn post_id(url: &Url) -> Option<String> {
+    let segments = url.path_segments()?.collect::<Vec<&str>>();
+
+    let post_id = match url.host_str()? {
+        "redd.it" => segments.first()?,
+        _ if host_matches(url, "reddit.com") => {
+            let position = segments
+                .iter()
+                .position(|segment| *segment == "comments" || *segment == "gallery")?;
+            segments.get(position + 1)?
+        }
+        _ => return None,
+    };
+
+    match !post_id.is_empty() && post_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        true => Some(post_id.to_lowercase()),
+        false => None,
+    }
 }
 
-fn extract_img_url(json: &serde_json::Value) -> LoadResult<RedditFileUrl> {
-    let url = json
-        .as_array()
-        .unwrap()
-        .get(0)
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("data")
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("children")
-        .unwrap()
-        .as_array()
-        .unwrap()
-        .get(0)
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("data")
-        .unwrap()
-        .as_object()
-        .unwrap()
-        .get("url")
-        .unwrap()
-        .as_str()
-        .ok_or("Not an Image")?
-        .to_string();
-
-    Ok(Image(url))
+async fn download_image(
+    client: &Client,
+    image_url: &str,
+    working_dir: &Path,
+) -> LoadResult<PathBuf> {
+    let image_url = Url::parse(image_url).map_err(|err| LoadError::Internal(err.into()))?;
+    let image_file_extension = image_url.path().rsplit('.').next().unwrap_or_default();
+    let filename = Uuid::new_v4().to_string() + "." + image_file_extension;
+
+    let path = working_dir.join(filename);
+    download_to(client, &image_url, &path).await?;
+
+    //We simply try to open the file as an Image, if it fails we wrote the Bytes of
+    // a text Post to the File
+    let format = ImageReader::open(&path)?
+        .decode()
+        .ok()
+        .and_then(|_| ImageFormat::from_path(&path).ok());
+    let Some(format) = format else {
+        let _ = fs::remove_file(&path);
+        return Err(LoadError::Ignore("This is a text post".into()));
+    };
+
+    //Because gifs are so fucking huge we convert the gif to an mp4 file
+    match format {
+        ImageFormat::Gif => convert_gif_to_mp4(path).await,
+        _ => Ok(path),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    fn test_reddit_image() {}
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::loaderror::LoadError;
+    use crate::reddit::model::{parse_post, RedditFileUrl};
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = fixture_dir().join(name);
+        fs::read(&path).unwrap_or_else(|_| panic!("Missing fixture {}", path.display()))
+    }
+
+    fn fixture_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/reddit")
+    }
+
+    fn post_id(url: &str) -> Option<String> {
+        super::post_id(&url::Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_post_id_from_every_url_form() {
+        let expected = Some("141abcd".to_string());
+        for url in [
+            "https://www.reddit.com/r/memes/comments/141abcd/monday_again/",
+            "https://www.reddit.com/r/memes/comments/141abcd/monday_again",
+            "https://reddit.com/r/memes/comments/141abcd/",
+            "https://old.reddit.com/r/memes/comments/141abcd/monday_again/",
+            "https://np.reddit.com/r/memes/comments/141abcd/monday_again/",
+            "https://m.reddit.com/r/memes/comments/141abcd/monday_again/?utm_source=share",
+            "https://www.reddit.com/r/memes/comments/141abcd/monday_again/?share_id=xyz#lightbox",
+            "https://www.reddit.com/comments/141abcd",
+            "https://www.reddit.com/gallery/141abcd",
+            "https://redd.it/141abcd",
+        ] {
+            assert_eq!(post_id(url), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn test_links_without_post_id_need_a_redirect() {
+        assert_eq!(post_id("https://www.reddit.com/r/memes/s/AbCdEf12"), None);
+        assert_eq!(post_id("https://v.redd.it/a1b2c3d4e5f6g"), None);
+        assert_eq!(post_id("https://www.reddit.com/r/memes/"), None);
+    }
+
+    #[test]
+    fn test_crosspost_uses_parent_media() {
+        let post = parse_post(&fixture("crosspost.json")).unwrap();
+        let RedditFileUrl::Video(video) = post.file_url().unwrap() else {
+            panic!("Expected a video");
+        };
+        assert_eq!(
+            video.fallback_url,
+            "https://v.redd.it/a1b2c3d4e5f6g/DASH_720.mp4?source=fallback"
+        );
+        assert_eq!(video.has_audio, Some(true));
+        assert_eq!(video.duration, Some(14));
+    }
+
+    #[test]
+    fn test_every_fixture_parses_without_panic() {
+        for entry in fs::read_dir(fixture_dir()).unwrap() {
+            let body = fs::read(entry.unwrap().path()).unwrap();
+            let _ = parse_post(&body).and_then(|post| post.file_url());
+        }
+    }
+
+    #[test]
+    fn test_reddit_image() {
+        let post = parse_post(&fixture("image.json")).unwrap();
+        assert_eq!(
+            post.file_url().unwrap(),
+            RedditFileUrl::Image("https://i.redd.it/5xk2q8y0vb4b1.jpg".into())
+        );
+    }
+
+    #[test]
+    fn test_reddit_video() {
+        let post = parse_post(&fixture("video.json")).unwrap();
+        let RedditFileUrl::Video(video) = post.file_url().unwrap() else {
+            panic!("Expected a video");
+        };
+        assert_eq!(
+            video.fallback_url,
+            "https://v.redd.it/a1b2c3d4e5f6g/DASH_720.mp4?source=fallback"
+        );
+        assert_eq!(video.has_audio, Some(true));
+        assert_eq!(video.duration, Some(14));
+    }
+
+    #[test]
+    fn test_reddit_gallery_keeps_order() {
+        let post = parse_post(&fixture("gallery.json")).unwrap();
+        assert_eq!(
+            post.file_url().unwrap(),
+            RedditFileUrl::Gallery(vec![
+                "q9w8e7r6t5y4.jpg".into(),
+                "a1s2d3f4g5h6.png".into(),
+                "z1x2c3v4b5n6.gif".into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_reddit_text() {
+        let post = parse_post(&fixture("text.json")).unwrap();
+        assert!(matches!(post.file_url(), Err(LoadError::Ignore(_))));
+    }
+
+    #[test]
+    fn test_reddit_gif() {
+        let post = parse_post(&fixture("gif.json")).unwrap();
+        assert_eq!(
+            post.file_url().unwrap(),
+            RedditFileUrl::Image("https://i.redd.it/g1f2g3f4g5f6.gif".into())
+        );
+    }
 
-    fn test_reddit_video() {}
+    #[test]
+    fn test_reddit_deleted() {
+        let post = parse_post(&fixture("deleted.json")).unwrap();
+        assert!(matches!(post.file_url(), Err(LoadError::Ignore(_))));
+    }
 
-    fn test_reddit_text() {}
+    #[test]
+    fn test_reddit_nsfw_interstitial() {
+        assert!(matches!(
+            parse_post(&fixture("nsfw_interstitial.html")),
+            Err(LoadError::AgeRestricted)
+        ));
+    }
 
-    fn test_reddit_gif() {}
+    #[test]
+    fn test_reddit_schema_change() {
+        assert!(matches!(
+            parse_post(&fixture("malformed.json")),
+            Err(LoadError::Internal(_))
+        ));
+    }
 }
@@ -0,0 +1,444 @@
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::Client;
+use serde::Deserialize;
+use tracing::info;
+use url::Url;
+
+use crate::loaderror::{CheckStatus, LoadError, LoadResult};
+use crate::reddit::model::RedditVideo;
+use crate::USER_AGENT;
+
+/// Older posts only have a single audio track with this name, newer ones have
+/// `DASH_AUDIO_64.mp4` and `DASH_AUDIO_128.mp4`. We only guess them when there is no playlist.
+const AUDIO_FALLBACK_NAMES: [&str; 3] =
+    ["DASH_AUDIO_128.mp4", "DASH_AUDIO_64.mp4", "DASH_audio.mp4"];
+
+#[derive(Deserialize)]
+struct Mpd {
+    #[serde(rename = "Period", default)]
+    periods: Vec<Period>,
+}
+
+#[derive(Deserialize)]
+struct Period {
+    #[serde(rename = "AdaptationSet", default)]
+    adaptation_sets: Vec<AdaptationSet>,
+}
+
+#[derive(Deserialize)]
+struct AdaptationSet {
+    #[serde(rename = "@contentType")]
+    content_type: Option<String>,
+    #[serde(rename = "@mimeType")]
+    mime_type: Option<String>,
+    #[serde(rename = "Representation", default)]
+    representations: Vec<Representation>,
+}
+
+#[derive(Deserialize)]
+struct Representation {
+    #[serde(rename = "@bandwidth")]
+    bandwidth: u64,
+    #[serde(rename = "@width")]
+    width: Option<u32>,
+    #[serde(rename = "@height")]
+    height: Option<u32>,
+    #[serde(rename = "@mimeType")]
+    mime_type: Option<String>,
+    #[serde(rename = "BaseURL")]
+    base_url: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Kind {
+    Video,
+    Audio,
+}
+
+/// One video or audio track reddit offers for a post
+#[derive(Debug)]
+pub(crate) struct Rendition {
+    pub kind: Kind,
+    pub url: Url,
+    /// Bits per second, used to estimate the size when reddit does not tell us
+    pub bandwidth: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Size in bytes taken from the Content-Length header
+    pub size: Option<u64>,
+}
+
+impl Rendition {
+    /// The shorter side, what the 720 in 720p stands for
+    fn resolution(&self) -> Option<u32> {
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => Some(width.min(height)),
+            (width, height) => width.or(height),
+        }
+    }
+
+    /// Size in bytes, estimated from the bandwidth and the length of the video when reddit
+    /// does not tell us
+    fn size(&self, duration: Option<u64>) -> Option<u64> {
+        self.size
+            .or_else(|| duration.map(|secs| self.bandwidth * secs / 8))
+            .filter(|size| *size > 0)
+    }
+}
+
+/// Lists every rendition of the video including its size. When the DASH playlist can not be used
+/// or has no video in it we fall back to the `fallback_url` and guess the audio track names.
+pub(crate) async fn renditions(client: &Client, video: &RedditVideo) -> LoadResult<Vec<Rendition>> {
+    let mut renditions = match &video.dash_url {
+        Some(dash_url) => match playlist(client, dash_url).await {
+            Ok(renditions) if renditions.iter().any(|r| r.kind == Kind::Video) => renditions,
+            Ok(_) => {
+                info!("DASH playlist {dash_url} has no video, falling back");
+                fallback_renditions(video)?
+            }
+            Err(err) => {
+                info!("Could not use DASH playlist {dash_url}, falling back: {err}");
+                fallback_renditions(video)?
+            }
+        },
+        None => fallback_renditions(video)?,
+    };
+
+    for rendition in &mut renditions {
+        rendition.size = match content_length(client, &rendition.url).await {
+            Ok(size) => size,
+            //Guessed audio tracks often do not exist and playlist tracks can still be estimated
+            //from their bandwidth. Without either we would only report a size of 0 as too large.
+            Err(_) if rendition.kind == Kind::Audio || rendition.bandwidth > 0 => None,
+            Err(err) => return Err(err),
+        };
+    }
+
+    //Guessed audio tracks that dont exist would only produce broken ffmpeg inputs
+    renditions.retain(|rendition| rendition.kind == Kind::Video || rendition.size.is_some());
+    Ok(renditions)
+}
+
+async fn playlist(client: &Client, dash_url: &str) -> LoadResult<Vec<Rendition>> {
+    let base = Url::parse(dash_url).map_err(|err| LoadError::Internal(err.into()))?;
+    let xml = client
+        .get(base.clone())
+        .header("user-agent", USER_AGENT)
+        .send()
+        .await?
+        .check_status()?
+        .text()
+        .await?;
+    parse(&xml, &base)
+}
+
+fn fallback_renditions(video: &RedditVideo) -> LoadResult<Vec<Rendition>> {
+    let url = Url::parse(&video.fallback_url).map_err(|err| LoadError::Internal(err.into()))?;
+    let mut renditions = vec![Rendition {
+        kind: Kind::Video,
+        url: url.clone(),
+        bandwidth: 0,
+        width: None,
+        height: None,
+        size: None,
+    }];
+
+    if video.has_audio != Some(false) {
+        for name in AUDIO_FALLBACK_NAMES {
+            let Ok(audio_url) = url.join(name) else {
+                continue;
+            };
+            renditions.push(Rendition {
+                kind: Kind::Audio,
+                url: audio_url,
+                bandwidth: 0,
+                width: None,
+                height: None,
+                size: None,
+            });
+        }
+    }
+    Ok(renditions)
+}
+
+/// Size of the track from a HEAD request, `None` when the server does not tell
+async fn content_length(client: &Client, url: &Url) -> LoadResult<Option<u64>> {
+    let response = client
+        .head(url.clone())
+        .header("user-agent", USER_AGENT)
+        .send()
+        .await?
+        .check_status()?;
+
+    //The body of a HEAD response is empty so we have to read the header ourselves
+    Ok(response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok()))
+}
+
+/// Parses a DASH playlist, the urls of the renditions are relative to the playlist url
+pub(crate) fn parse(xml: &str, base: &Url) -> LoadResult<Vec<Rendition>> {
+    let mpd = quick_xml::de::from_str::<Mpd>(xml)
+        .map_err(|err| LoadError::Internal(format!("Invalid DASH playlist: {err}").into()))?;
+
+    let mut renditions = Vec::new();
+    for set in mpd
+        .periods
+        .iter()
+        .flat_map(|period| &period.adaptation_sets)
+    {
+        for representation in &set.representations {
+            let content_type = set
+                .content_type
+                .as_deref()
+                .or(set.mime_type.as_deref())
+                .or(representation.mime_type.as_deref())
+                .unwrap_or_default();
+            let kind = match content_type {
+                t if t.starts_with("video") => Kind::Video,
+                t if t.starts_with("audio") => Kind::Audio,
+                _ => continue,
+            };
+            let url = base
+                .join(representation.base_url.trim())
+                .map_err(|err| LoadError::Internal(err.into()))?;
+
+            renditions.push(Rendition {
+                kind,
+                url,
+                bandwidth: representation.bandwidth,
+                width: representation.width,
+                height: representation.height,
+                size: None,
+            });
+        }
+    }
+    Ok(renditions)
+}
+
+/// Picks the highest resolution video up to `max_resolution`, together with the best audio
+/// track, whose combined size fits into `limit` bytes. Silent videos, or posts without
+/// `has_audio`, get no audio track. When no video fits together with an audio track the best
+/// video that fits on its own is posted without sound.
+pub(crate) fn select(
+    renditions: &[Rendition],
+    duration: Option<u64>,
+    with_audio: bool,
+    limit: u64,
+    max_resolution: Option<u32>,
+) -> Option<(&Rendition, Option<&Rendition>)> {
+    let size = |rendition: &Rendition| rendition.size(duration);
+
+    let mut videos = renditions
+        .iter()
+        .filter(|rendition| rendition.kind == Kind::Video)
+        .filter(|video| {
+            max_resolution.is_none_or(|max| video.resolution().is_none_or(|res| res <= max))
+        })
+        .collect::<Vec<&Rendition>>();
+    videos.sort_by_key(|video| std::cmp::Reverse((video.height, video.bandwidth, size(video))));
+
+    let mut audios = renditions
+        .iter()
+        .filter(|rendition| with_audio && rendition.kind == Kind::Audio)
+        .collect::<Vec<&Rendition>>();
+    audios.sort_by_key(|audio| std::cmp::Reverse((audio.bandwidth, size(audio))));
+
+    //A video with sound never loses it here, the caller decides whether a silent one will do
+    if audios.is_empty() {
+        return videos
+            .into_iter()
+            .find(|video| size(video).is_some_and(|video_size| video_size <= limit))
+            .map(|video| (video, None));
+    }
+    for video in &videos {
+        let Some(video_size) = size(video) else {
+            continue;
+        };
+        for audio in &audios {
+            if size(audio).is_some_and(|audio_size| video_size + audio_size <= limit) {
+                return Some((video, Some(audio)));
+            }
+        }
+    }
+    None
+}
+
+/// The smallest video together with the smallest audio track, for a caller that compresses videos
+/// which do not fit
+pub(crate) fn select_smallest(
+    renditions: &[Rendition],
+    duration: Option<u64>,
+    with_audio: bool,
+) -> Option<(&Rendition, Option<&Rendition>)> {
+    let smallest = |kind| {
+        renditions
+            .iter()
+            .filter(|rendition| rendition.kind == kind)
+            .filter(|rendition| rendition.size(duration).is_some())
+            .min_by_key(|rendition| rendition.size(duration))
+    };
+    let video = smallest(Kind::Video)?;
+    Some((video, smallest(Kind::Audio).filter(|_| with_audio)))
+}
+
+/// The best audio track that is not larger than `limit` bytes, for audio only downloads
+pub(crate) fn select_audio(
+    renditions: &[Rendition],
+    duration: Option<u64>,
+    limit: u64,
+) -> Option<&Rendition> {
+    renditions
+        .iter()
+        .filter(|rendition| rendition.kind == Kind::Audio)
+        .filter(|audio| audio.size(duration).is_some_and(|size| size <= limit))
+        .max_by_key(|audio| audio.bandwidth)
+}
+
+/// Size in bytes of the smallest track of that kind, to tell the user how far off the limit is
+pub(crate) fn smallest_size(
+    renditions: &[Rendition],
+    duration: Option<u64>,
+    kind: Kind,
+) -> Option<u64> {
+    renditions
+        .iter()
+        .filter(|rendition| rendition.kind == kind)
+        .filter_map(|rendition| rendition.size(duration))
+        .min()
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use crate::reddit::dash::{parse, select, select_audio, select_smallest, Kind, Rendition};
+
+    const PLAYLIST: &str = include_str!("../../../tests/fixtures/reddit_dash/DASHPlaylist.mpd");
+    const SILENT: &str = include_str!("../../../tests/fixtures/reddit_dash/silent.mpd");
+
+    fn base() -> Url {
+        Url::parse("https://v.redd.it/a1b2c3d4e5f6g/DASHPlaylist.mpd?a=1&v=1&f=sd").unwrap()
+    }
+
+    fn file_names(selected: Option<(&Rendition, Option<&Rendition>)>) -> (String, Option<String>) {
+        let (video, audio) = selected.expect("Expected a rendition to fit");
+        let name =
+            |rendition: &Rendition| rendition.url.path().rsplit('/').next().unwrap().to_string();
+        (name(video), audio.map(name))
+    }
+
+    #[test]
+    fn test_parse_playlist() {
+        let renditions = parse(PLAYLIST, &base()).unwrap();
+
+        let videos = renditions.iter().filter(|r| r.kind == Kind::Video).count();
+        let audios = renditions.iter().filter(|r| r.kind == Kind::Audio).count();
+        assert_eq!((videos, audios), (4, 2));
+        assert_eq!(
+            renditions[0].url.as_str(),
+            "https://v.redd.it/a1b2c3d4e5f6g/DASH_220.mp4"
+        );
+    }
+
+    #[test]
+    fn test_select_best_rendition_that_fits() {
+        let renditions = parse(PLAYLIST, &base()).unwrap();
+
+        //14 seconds at 4.5MBit for 1080p and 128kBit audio is about 8MB
+        assert_eq!(
+            file_names(select(&renditions, Some(14), true, 25_000_000, None)),
+            ("DASH_1080.mp4".into(), Some("DASH_AUDIO_128.mp4".into()))
+        );
+        assert_eq!(
+            file_names(select(&renditions, Some(14), true, 5_000_000, None)),
+            ("DASH_720.mp4".into(), Some("DASH_AUDIO_128.mp4".into()))
+        );
+        assert!(select(&renditions, Some(14), true, 100_000, None).is_none());
+
+        //220p alone is about 525kB, there is no room left for an audio track. The sound is only
+        //dropped when the caller asks for a silent video.
+        assert!(select(&renditions, Some(14), true, 600_000, None).is_none());
+        assert_eq!(
+            file_names(select(&renditions, Some(14), false, 600_000, None)),
+            ("DASH_220.mp4".into(), None)
+        );
+
+        //Asking for at most 480p skips the larger tracks even though they would fit
+        assert_eq!(
+            file_names(select(&renditions, Some(14), true, 25_000_000, Some(480))),
+            ("DASH_480.mp4".into(), Some("DASH_AUDIO_128.mp4".into()))
+        );
+    }
+
+    #[test]
+    fn test_prefer_measured_size_over_estimate() {
+        let mut renditions = parse(PLAYLIST, &base()).unwrap();
+        for rendition in &mut renditions {
+            if rendition.url.path().ends_with("DASH_1080.mp4") {
+                rendition.size = Some(30_000_000);
+            }
+        }
+
+        assert_eq!(
+            file_names(select(&renditions, Some(14), true, 25_000_000, None)),
+            ("DASH_720.mp4".into(), Some("DASH_AUDIO_128.mp4".into()))
+        );
+    }
+
+    #[test]
+    fn test_silent_video_has_no_audio_track() {
+        let renditions = parse(SILENT, &base()).unwrap();
+        assert!(renditions.iter().all(|r| r.kind == Kind::Video));
+
+        assert_eq!(
+            file_names(select(&renditions, Some(8), true, 25_000_000, None)),
+            ("DASH_480.mp4".into(), None)
+        );
+
+        //has_audio = false on the post wins over audio tracks in the playlist
+        let renditions = parse(PLAYLIST, &base()).unwrap();
+        assert_eq!(
+            file_names(select(&renditions, Some(14), false, 25_000_000, None)),
+            ("DASH_1080.mp4".into(), None)
+        );
+    }
+
+    #[test]
+    fn test_select_smallest_for_compression() {
+        let renditions = parse(PLAYLIST, &base()).unwrap();
+
+        assert_eq!(
+            file_names(select_smallest(&renditions, Some(14), true)),
+            ("DASH_220.mp4".into(), Some("DASH_AUDIO_64.mp4".into()))
+        );
+        assert_eq!(
+            file_names(select_smallest(&renditions, Some(14), false)),
+            ("DASH_220.mp4".into(), None)
+        );
+    }
+
+    #[test]
+    fn test_select_audio_only() {
+        let renditions = parse(PLAYLIST, &base()).unwrap();
+        let name = |rendition: Option<&Rendition>| {
+            rendition.map(|audio| audio.url.path().rsplit('/').next().unwrap().to_string())
+        };
+
+        assert_eq!(
+            name(select_audio(&renditions, Some(14), 25_000_000)),
+            Some("DASH_AUDIO_128.mp4".into())
+        );
+        //14 seconds at 131kBit are about 229kB, only the 64kBit track fits into 200kB
+        assert_eq!(
+            name(select_audio(&renditions, Some(14), 200_000)),
+            Some("DASH_AUDIO_64.mp4".into())
+        );
+        assert_eq!(name(select_audio(&renditions, Some(14), 1_000)), None);
+
+        let silent = parse(SILENT, &base()).unwrap();
+        assert_eq!(name(select_audio(&silent, Some(8), 25_000_000)), None);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::loaderror::{LoadError, LoadResult};
+
+/// Reddit answers a post url with two listings, the first one contains the post and the second
+/// one the comments
+#[derive(Deserialize, Debug)]
+pub(crate) struct Listing {
+    pub data: ListingData,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ListingData {
+    #[serde(default)]
+    pub children: Vec<Child>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Child {
+    pub kind: String,
+    pub data: PostData,
+}
+
+/// Every field that is not present on all posts is optional, reddit leaves them out or sets them
+/// to null depending on the kind of post
+#[derive(Deserialize, Debug)]
+pub(crate) struct PostData {
+    pub id: String,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    /// Url of a small preview image, or a placeholder like `self`, `default` or `nsfw`
+    pub thumbnail: Option<String>,
+    #[serde(default)]
+    pub is_self: bool,
+    pub removed_by_category: Option<String>,
+    pub secure_media: Option<SecureMedia>,
+    pub media: Option<SecureMedia>,
+    pub gallery_data: Option<GalleryData>,
+    pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+    /// Crossposts dont have media on their own, it lives in the post they were crossposted from
+    pub crosspost_parent_list: Option<Vec<PostData>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct SecureMedia {
+    pub reddit_video: Option<RedditVideo>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RedditVideo {
+    /// Video track without audio in a fixed resolution
+    pub fallback_url: String,
+    /// Playlist with every video and audio track
+    pub dash_url: Option<String>,
+    pub has_audio: Option<bool>,
+    /// Length in seconds
+    pub duration: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct GalleryData {
+    #[serde(default)]
+    pub items: Vec<GalleryItem>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct GalleryItem {
+    pub media_id: String,
+}
+
+/// Only `status` is always there, images that failed processing on reddit's side have nothing else
+#[derive(Deserialize, Debug)]
+pub(crate) struct MediaMetadata {
+    pub status: String,
+    /// Mime type of the original file
+    pub m: Option<String>,
+}
+
+/// What we have to download for a post
+#[derive(Debug, PartialEq)]
+pub(crate) enum RedditFileUrl {
+    Image(String),
+    Video(RedditVideo),
+    /// The file names of the images of a gallery post on `i.redd.it`, in the order they are
+    /// shown on reddit
+    Gallery(Vec<String>),
+}
+
+/// Parses the response of a `.json` post url and returns the post, this never panics no matter
+/// what reddit sends us
+pub(crate) fn parse_post(body: &[u8]) -> LoadResult<PostData> {
+    let listings = serde_json::from_slice::<Vec<Listing>>(body).map_err(|err| {
+        //Without a login reddit answers NSFW posts with a page that asks for the age
+        if String::from_utf8_lossy(body).contains("/over18") {
+            return LoadError::AgeRestricted;
+        }
+        LoadError::Internal(
+            format!(
+                "Reddit did not answer with a post listing, the post might be private or \
+                 deleted: {err}"
+            )
+            .into(),
+        )
+    })?;
+
+    listings
+        .into_iter()
+        .next()
+        .and_then(|listing| {
+            listing
+                .data
+                .children
+                .into_iter()
+                .find(|child| child.kind == "t3")
+        })
+        .map(|child| child.data)
+        .ok_or_else(|| LoadError::Internal("Reddit listing does not contain a post".into()))
+}
+
+impl PostData {
+    pub(crate) fn reddit_video(&self) -> Option<&RedditVideo> {
+        self.secure_media
+            .as_ref()
+            .or(self.media.as_ref())
+            .and_then(|media| media.reddit_video.as_ref())
+    }
+
+    /// The preview image when the post has a real one
+    pub(crate) fn thumbnail_url(&self) -> Option<Url> {
+        Url::parse(self.thumbnail.as_deref()?)
+            .ok()
+            .filter(|url| url.scheme() == "https")
+    }
+
+    /// Works out which file(s) the post links to
+    pub(crate) fn file_url(&self) -> LoadResult<RedditFileUrl> {
+        if let Some(parent) = self.crosspost_parent() {
+            return parent.file_url();
+        }
+
+        if let Some(reason) = &self.removed_by_category {
+            return Err(LoadError::Ignore(format!(
+                "Post {} was removed ({reason})",
+                self.id
+            )));
+        }
+
+        if let Some(gallery) = self.gallery_files() {
+            return Ok(RedditFileUrl::Gallery(gallery));
+        }
+
+        if let Some(video) = self.reddit_video() {
+            return Ok(RedditFileUrl::Video(video.clone()));
+        }
+
+        match &self.url {
+            Some(_) if self.is_self => Err(LoadError::Ignore("This is a text post".into())),
+            Some(url) => Ok(RedditFileUrl::Image(url.clone())),
+            None => Err(LoadError::Ignore("This post has no media".into())),
+        }
+    }
+
+    fn crosspost_parent(&self) -> Option<&PostData> {
+        self.crosspost_parent_list.as_ref()?.first()
+    }
+
+    /// For gallery posts `url` points to the gallery itself, the images are listed in
+    /// `gallery_data.items` (for the order) and `media_metadata` (for the file type)
+    fn gallery_files(&self) -> Option<Vec<String>> {
+        let items = &self.gallery_data.as_ref()?.items;
+        let metadata = self.media_metadata.as_ref()?;
+
+        let files = items
+            .iter()
+            .filter_map(|item| {
+                let media = metadata.get(&item.media_id)?;
+                if media.status != "valid" {
+                    return None;
+                }
+                let extension = match media.m.as_deref()? {
+                    "image/jpg" | "image/jpeg" => "jpg",
+                    "image/png" => "png",
+                    "image/gif" => "gif",
+                    "image/webp" => "webp",
+                    _ => return None,
+                };
+                Some(format!("{}.{extension}", item.media_id))
+            })
+            .collect::<Vec<String>>();
+
+        match files.is_empty() {
+            true => None,
+            false => Some(files),
+        }
+    }
+}
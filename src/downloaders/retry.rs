@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::loaderror::LoadError;
+
+/// How often a download that failed because of a hiccup is tried again and how long we wait in
+/// between. The wait doubles with every attempt and is randomized, so downloads that failed
+/// together do not hit the platform together again.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Tries in total, 1 turns retrying off
+    pub max_attempts: u32,
+    /// Wait after the first failed attempt
+    pub base_delay_ms: u64,
+    /// Longest wait, a `Retry-After` longer than this is not waited for
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 2000,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before trying again after `attempt`, counted from 1, failed with `err`.
+    /// `None` when the error is permanent or we are out of attempts.
+    pub fn delay(&self, attempt: u32, err: &LoadError) -> Option<Duration> {
+        if attempt >= self.max_attempts || !err.is_retryable() {
+            return None;
+        }
+        let max_delay = Duration::from_millis(self.max_delay_ms);
+        if let LoadError::RateLimited {
+            retry_after: Some(retry_after),
+        } = err
+        {
+            return (*retry_after <= max_delay).then_some(*retry_after);
+        }
+
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_delay_ms);
+        let jittered = rand::thread_rng().gen_range(backoff / 2..=backoff);
+        Some(Duration::from_millis(jittered))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::loaderror::LoadError;
+    use crate::retry::RetryPolicy;
+
+    const HICCUP: LoadError = LoadError::RateLimited { retry_after: None };
+
+    #[test]
+    fn test_backoff_grows_until_the_last_attempt() {
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            base_delay_ms: 1000,
+            max_delay_ms: 3000,
+        };
+
+        let first = policy.delay(1, &HICCUP).unwrap();
+        assert!(first >= Duration::from_millis(500) && first <= Duration::from_millis(1000));
+        let second = policy.delay(2, &HICCUP).unwrap();
+        assert!(second >= Duration::from_millis(1000) && second <= Duration::from_millis(2000));
+        let capped = policy.delay(3, &HICCUP).unwrap();
+        assert!(capped >= Duration::from_millis(1500) && capped <= Duration::from_millis(3000));
+        assert_eq!(policy.delay(4, &HICCUP), None);
+    }
+
+    #[test]
+    fn test_permanent_errors_are_not_retried() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(1, &LoadError::NotFound), None);
+        assert_eq!(
+            policy.delay(
+                1,
+                &LoadError::TooLarge {
+                    size: None,
+                    limit: 1
+                }
+            ),
+            None
+        );
+        assert_eq!(
+            RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            }
+            .delay(1, &HICCUP),
+            None
+        );
+    }
+
+    #[test]
+    fn test_retry_after_is_respected() {
+        let policy = RetryPolicy::default();
+        let err = |secs| LoadError::RateLimited {
+            retry_after: Some(Duration::from_secs(secs)),
+        };
+
+        assert_eq!(policy.delay(1, &err(5)), Some(Duration::from_secs(5)));
+        assert_eq!(policy.delay(1, &err(600)), None);
+    }
+}
@@ -1,9 +1,273 @@
-use std::error::Error;
+use std::cmp::Ordering;
 use std::path::PathBuf;
 
-use serenity::model::channel::Message;
+use reqwest::Client;
+use serde::Deserialize;
+use serenity::async_trait;
+use tracing::info;
+use url::Url;
+use uuid::Uuid;
 
-//We are allocating a dynamic PathBuf on the Heap, we could use lifetimes to use a Path Object on the stack instead
-pub fn _load(_url: &str, _msg: &Message) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
-    todo!();
+use crate::loaderror::{LoadError, LoadResult};
+use crate::{
+    create_working_dir, host_matches, mbyte_to_byte, ytdlp, Downloader, LoadOptions, TEMP_DIR,
+    USER_AGENT,
+};
+
+pub struct TikTok;
+
+#[async_trait]
+impl Downloader for TikTok {
+    fn name(&self) -> &'static str {
+        "tiktok"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        host_matches(url, "tiktok.com")
+    }
+
+    fn media_id(&self, url: &Url) -> Option<String> {
+        video_id(url)
+    }
+
+    async fn load(&self, url: &Url, options: &LoadOptions) -> LoadResult<Vec<PathBuf>> {
+        load(url, options).await.map(|path| vec![path])
+    }
+}
+
+/// The parts of the `yt-dlp --dump-json` output we need to pick a format
+#[derive(Deserialize)]
+struct VideoInfo {
+    #[serde(default)]
+    formats: Vec<Format>,
+}
+
+#[derive(Deserialize)]
+struct Format {
+    format_id: String,
+    ext: Option<String>,
+    vcodec: Option<String>,
+    format_note: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    tbr: Option<f64>,
+    filesize: Option<u64>,
+    filesize_approx: Option<u64>,
+}
+
+impl Format {
+    fn is_video(&self) -> bool {
+        self.vcodec.as_deref().is_some_and(|codec| codec != "none")
+    }
+
+    /// TikTok serves every video twice, the `download_addr` variant has the watermark burned in
+    fn is_watermarked(&self) -> bool {
+        self.format_id.starts_with("download")
+            || self
+                .format_note
+                .as_deref()
+                .is_some_and(|note| note.contains("watermark"))
+    }
+
+    /// The shorter side, what the 720 in 720p stands for
+    fn resolution(&self) -> Option<u32> {
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => Some(width.min(height)),
+            (width, height) => width.or(height),
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.filesize.or(self.filesize_approx)
+    }
+}
+
+pub async fn load(url: &Url, options: &LoadOptions) -> LoadResult<PathBuf> {
+    let url = resolve_short_link(url).await?;
+    if is_slideshow(&url) {
+        return Err(slideshow());
+    }
+
+    let output = ytdlp::run(&["--dump-json".to_string(), url.to_string()]).await?;
+    let info = serde_json::from_slice::<VideoInfo>(&output.stdout)
+        .map_err(|err| LoadError::Internal(Box::new(err)))?;
+    let format = select_format(
+        &info,
+        options.max_filesize,
+        options.max_resolution,
+        options.compress,
+    )?;
+    info!("Downloading TikTok {url} in format {}", format.format_id);
+
+    let working_dir = TEMP_DIR.get_or_try_init(create_working_dir)?;
+    let filename = Uuid::new_v4().to_string() + ".mp4";
+    ytdlp::run(&[
+        "-f".to_string(),
+        format.format_id.clone(),
+        "-o".to_string(),
+        filename.clone(),
+        url.to_string(),
+    ])
+    .await?;
+
+    Ok(working_dir.join(filename))
+}
+
+/// `vm.tiktok.com/<token>` and `tiktok.com/t/<token>` links only redirect to the real video url
+fn is_short_link(url: &Url) -> bool {
+    host_matches(url, "vm.tiktok.com")
+        || host_matches(url, "vt.tiktok.com")
+        || url.path().starts_with("/t/")
+}
+
+async fn resolve_short_link(url: &Url) -> LoadResult<Url> {
+    if !is_short_link(url) {
+        return Ok(url.clone());
+    }
+
+    let response = Client::new()
+        .get(url.clone())
+        .header("user-agent", USER_AGENT)
+        .send()
+        .await?;
+    info!("Resolved TikTok short link {url} to {}", response.url());
+    Ok(response.url().clone())
+}
+
+/// The numeric id of `tiktok.com/@user/video/<id>`, short links only have it after resolving them
+fn video_id(url: &Url) -> Option<String> {
+    let mut segments = url.path_segments()?;
+    segments.find(|segment| *segment == "video")?;
+    segments
+        .next()
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+fn is_slideshow(url: &Url) -> bool {
+    url.path_segments()
+        .is_some_and(|mut segments| segments.any(|segment| segment == "photo"))
+}
+
+/// Picks the highest quality watermark free video that fits into the size limit and is not
+/// higher than `max_resolution`, with `compress` the smallest one when none fits. Slideshows that
+/// made it through the url check are recognized by not having any video format.
+fn select_format(
+    info: &VideoInfo,
+    max_filesize: u16,
+    max_resolution: Option<u32>,
+    compress: bool,
+) -> LoadResult<&Format> {
+    let videos = info
+        .formats
+        .iter()
+        .filter(|format| format.is_video() && !format.is_watermarked())
+        .filter(|format| format.ext.as_deref().is_none_or(|ext| ext == "mp4"))
+        .collect::<Vec<&Format>>();
+
+    if videos.is_empty() {
+        return Err(slideshow());
+    }
+
+    let limit = mbyte_to_byte(max_filesize.into());
+    let smallest = videos
+        .iter()
+        .filter(|format| format.size().is_some())
+        .min_by_key(|format| format.size())
+        .copied();
+    let best = videos
+        .into_iter()
+        .filter(|format| format.size().is_none_or(|size| size <= limit))
+        .filter(|format| {
+            max_resolution.is_none_or(|max| format.resolution().is_none_or(|res| res <= max))
+        })
+        .max_by(|a, b| {
+            a.height
+                .cmp(&b.height)
+                .then(a.tbr.partial_cmp(&b.tbr).unwrap_or(Ordering::Equal))
+        });
+    match (best, smallest) {
+        (Some(format), _) => Ok(format),
+        (None, Some(format)) if compress => Ok(format),
+        (None, smallest) => Err(LoadError::TooLarge {
+            size: smallest.and_then(Format::size),
+            limit,
+        }),
+    }
+}
+
+fn slideshow() -> LoadError {
+    LoadError::Unsupported {
+        platform: "TikTok",
+        reason: "TikTok photo slideshows are not supported".into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use crate::loaderror::LoadError;
+    use crate::tiktok::{is_short_link, is_slideshow, select_format, video_id, VideoInfo};
+
+    const VIDEO: &str = include_str!("../../tests/fixtures/tiktok/video.json");
+    const SLIDESHOW: &str = include_str!("../../tests/fixtures/tiktok/slideshow.json");
+
+    #[test]
+    fn test_select_watermark_free_format() {
+        let info = serde_json::from_str::<VideoInfo>(VIDEO).unwrap();
+
+        let format = select_format(&info, 25, None, false).unwrap();
+        assert_eq!(format.format_id, "bytevc1_1080p_1213339-0");
+
+        //The 1080p variant is too large for 2MB so we fall back to the smaller one
+        let format = select_format(&info, 2, None, false).unwrap();
+        assert_eq!(format.format_id, "h264_540p_666613-0");
+
+        //The same one is picked when we ask for at most 720p
+        let format = select_format(&info, 25, Some(720), false).unwrap();
+        assert_eq!(format.format_id, "h264_540p_666613-0");
+    }
+
+    #[test]
+    fn test_reject_oversized_video() {
+        let info = serde_json::from_str::<VideoInfo>(VIDEO).unwrap();
+        assert!(matches!(
+            select_format(&info, 1, None, false),
+            Err(LoadError::TooLarge {
+                limit: 1_000_000,
+                ..
+            })
+        ));
+
+        //The handler compresses the smallest one when it is allowed to
+        let format = select_format(&info, 1, None, true).unwrap();
+        assert_eq!(format.format_id, "bytevc1_540p_412503-0");
+    }
+
+    #[test]
+    fn test_reject_slideshow() {
+        let info = serde_json::from_str::<VideoInfo>(SLIDESHOW).unwrap();
+        assert!(matches!(
+            select_format(&info, 25, None, false),
+            Err(LoadError::Unsupported { .. })
+        ));
+
+        let url = Url::parse("https://www.tiktok.com/@user/photo/7234564829912").unwrap();
+        assert!(is_slideshow(&url));
+    }
+
+    #[test]
+    fn test_short_links() {
+        let short = Url::parse("https://vm.tiktok.com/ZGJxyz123/").unwrap();
+        let short_t = Url::parse("https://www.tiktok.com/t/ZTRxyz123/").unwrap();
+        let full = Url::parse("https://www.tiktok.com/@user/video/7234564829912").unwrap();
+
+        assert!(is_short_link(&short));
+        assert!(is_short_link(&short_t));
+        assert!(!is_short_link(&full));
+        assert!(!is_slideshow(&full));
+        assert_eq!(video_id(&full), Some("7234564829912".into()));
+        assert_eq!(video_id(&short), None);
+    }
 }
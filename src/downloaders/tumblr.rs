@@ -1,9 +1,361 @@
-use std::error::Error;
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use serenity::model::channel::Message;
+use reqwest::Client;
+use serde_json::Value;
+use serenity::async_trait;
+use tracing::info;
+use url::Url;
+use uuid::Uuid;
 
-//We are allocating a dynamic PathBuf on the Heap, we could use lifetimes to use a Path Object on the stack instead
-pub fn _load(_url: &str, _msg: &Message) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
-    todo!()
+use crate::loaderror::{CheckStatus, LoadError, LoadResult};
+use crate::{
+    convert_gif_to_mp4, create_working_dir, host_matches, mbyte_to_byte, Downloader, LoadOptions,
+    TEMP_DIR, USER_AGENT,
+};
+
+pub struct Tumblr {
+    client: Client,
+    /// The page of a post is requested from `<base_url>/<blog>/<id>`
+    base_url: Url,
+}
+
+impl Tumblr {
+    /// Tests use this to send the requests to a local server
+    pub fn new(client: Client, base_url: Url) -> Self {
+        Tumblr { client, base_url }
+    }
+}
+
+impl Default for Tumblr {
+    fn default() -> Self {
+        Tumblr::new(
+            Client::new(),
+            Url::parse("https://www.tumblr.com/").expect("Valid tumblr url"),
+        )
+    }
+}
+
+#[async_trait]
+impl Downloader for Tumblr {
+    fn name(&self) -> &'static str {
+        "tumblr"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        host_matches(url, "tumblr.com")
+    }
+
+    fn media_id(&self, url: &Url) -> Option<String> {
+        parse_post_url(url).map(|(blog, post_id)| format!("{blog}/{post_id}"))
+    }
+
+    async fn load(&self, url: &Url, options: &LoadOptions) -> LoadResult<Vec<PathBuf>> {
+        load(&self.client, &self.base_url, url, options).await
+    }
+}
+
+/// The post page stores its state, including the post in the Neue Post Format (NPF), as json in a
+/// script tag that assigns it to this variable
+const INITIAL_STATE: &str = "window['___INITIAL_STATE___'] = ";
+
+/// Videos are downloaded up to this size when the caller compresses them, larger ones would not
+/// get below the upload limit in time anyway
+const MAX_COMPRESSIBLE_BYTES: u64 = 200 * 1000 * 1000;
+
+#[derive(Debug, PartialEq)]
+enum Media {
+    Image { url: String, extension: String },
+    Video { url: String },
+}
+
+pub async fn load(
+    client: &Client,
+    base_url: &Url,
+    url: &Url,
+    options: &LoadOptions,
+) -> LoadResult<Vec<PathBuf>> {
+    let Some((blog, post_id)) = parse_post_url(url) else {
+        return Err(LoadError::Ignore(format!(
+            "{url} is not a link to a tumblr post"
+        )));
+    };
+
+    let page_url = base_url
+        .join(&format!("{blog}/{post_id}"))
+        .map_err(|err| LoadError::Internal(err.into()))?;
+    let page = client
+        .get(page_url)
+        .header("user-agent", USER_AGENT)
+        .send()
+        .await?
+        .check_status()?
+        .text()
+        .await?;
+
+    let media = extract_media(&page, &post_id)?;
+    if media.is_empty() {
+        return Err(LoadError::Ignore("This is a text post".into()));
+    }
+    info!(
+        "Tumblr post {blog}/{post_id} contains {} files",
+        media.len()
+    );
+
+    let working_dir = TEMP_DIR.get_or_try_init(create_working_dir)?;
+    let limit = mbyte_to_byte(options.max_filesize.into());
+    let video_limit = match options.compress {
+        true => limit.max(MAX_COMPRESSIBLE_BYTES),
+        false => limit,
+    };
+    let mut paths = Vec::with_capacity(media.len());
+    let mut errors = Vec::new();
+    for media in media {
+        let media_limit = match media {
+            Media::Image { ref extension, .. } if extension != "gif" => limit,
+            _ => video_limit,
+        };
+        match download_media(client, &media, working_dir, media_limit).await {
+            Ok(path) => paths.push(path),
+            Err(err) => {
+                info!("Could not load {media:?} of tumblr post {blog}/{post_id}: {err}");
+                errors.push(err);
+            }
+        }
+    }
+
+    //The rest of the post is still worth posting when the caller tells the user what is missing
+    match (&options.notes, errors.is_empty()) {
+        (_, true) => Ok(paths),
+        (Some(notes), false) if !paths.is_empty() => {
+            errors.into_iter().for_each(|err| notes.failed(err));
+            Ok(paths)
+        }
+        _ => {
+            paths.iter().for_each(|path| {
+                let _ = fs::remove_file(path);
+            });
+            Err(errors.remove(0))
+        }
+    }
+}
+
+async fn download_media(
+    client: &Client,
+    media: &Media,
+    working_dir: &Path,
+    limit: u64,
+) -> LoadResult<PathBuf> {
+    let (url, extension) = match media {
+        Media::Image { url, extension } => (url, extension.as_str()),
+        Media::Video { url } => (url, "mp4"),
+    };
+    let path = working_dir.join(Uuid::new_v4().to_string() + "." + extension);
+
+    //Because gifs are so fucking huge we convert the gif to an mp4 file, only the mp4 has to fit
+    if extension != "gif" {
+        download_to(client, url, &path, limit).await?;
+        return Ok(path);
+    }
+    download_to(client, url, &path, u64::MAX).await?;
+    let path = convert_gif_to_mp4(path).await?;
+    let size = fs::metadata(&path)?.len();
+    if size > limit {
+        let _ = fs::remove_file(&path);
+        return Err(LoadError::TooLarge {
+            size: Some(size),
+            limit,
+        });
+    }
+    Ok(path)
+}
+
+/// Tumblr does not tell us the size of the media before we request it, so the download stops
+/// as soon as the file gets larger than `limit`
+async fn download_to(client: &Client, url: &str, path: &Path, limit: u64) -> LoadResult<()> {
+    let mut response = client.get(url).send().await?.check_status()?;
+    if let Some(size) = response.content_length().filter(|size| *size > limit) {
+        return Err(LoadError::TooLarge {
+            size: Some(size),
+            limit,
+        });
+    }
+
+    let mut file = File::create(path)?;
+    let mut written = 0;
+    let result = async {
+        while let Some(chunk) = response.chunk().await? {
+            written += chunk.len() as u64;
+            if written > limit {
+                return Err(LoadError::TooLarge { size: None, limit });
+            }
+            file.write_all(&chunk)?;
+        }
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// Returns blog name and post id for `tumblr.com/<blog>/<id>` and `<blog>.tumblr.com/post/<id>`
+fn parse_post_url(url: &Url) -> Option<(String, String)> {
+    let host = url.host_str()?;
+    let segments = url.path_segments()?.collect::<Vec<&str>>();
+
+    let (blog, post_id) = match host.strip_suffix(".tumblr.com") {
+        Some("www") | None => (*segments.first()?, *segments.get(1)?),
+        Some(blog) if segments.first() == Some(&"post") => (blog, *segments.get(1)?),
+        Some(_) => return None,
+    };
+
+    if blog.is_empty() || post_id.is_empty() || !post_id.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((blog.to_string(), post_id.to_string()))
+}
+
+/// Collects every image, gif and video of the post in the order they appear, including the
+/// content of reblogged posts in the trail
+fn extract_media(page: &str, post_id: &str) -> LoadResult<Vec<Media>> {
+    let state = page
+        .split_once(INITIAL_STATE)
+        .and_then(|(_, rest)| rest.split_once("</script>"))
+        .map(|(json, _)| json.trim().trim_end_matches(';'))
+        .ok_or_else(|| LoadError::Internal("Tumblr page contains no initial state".into()))?;
+    let state =
+        serde_json::from_str::<Value>(state).map_err(|err| LoadError::Internal(err.into()))?;
+
+    let post = find_post(&state, post_id).ok_or(LoadError::NotFound)?;
+
+    let trail = post
+        .get("trail")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|reblog| reblog.get("content"));
+    let blocks = trail
+        .chain(post.get("content"))
+        .filter_map(Value::as_array)
+        .flatten();
+
+    Ok(blocks.filter_map(media_from_block).collect())
+}
+
+fn find_post<'a>(value: &'a Value, post_id: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(object) => {
+            //Ids that do not fit into a js number are only correct in `idString`
+            let id = object
+                .get("id")
+                .and_then(Value::as_str)
+                .or_else(|| object.get("idString").and_then(Value::as_str));
+            if id == Some(post_id) && object.contains_key("content") {
+                return Some(value);
+            }
+            object.values().find_map(|value| find_post(value, post_id))
+        }
+        Value::Array(array) => array.iter().find_map(|value| find_post(value, post_id)),
+        _ => None,
+    }
+}
+
+fn media_from_block(block: &Value) -> Option<Media> {
+    match block.get("type")?.as_str()? {
+        "image" => {
+            //Every size tumblr has of the image, we want the largest one
+            let image = block
+                .get("media")?
+                .as_array()?
+                .iter()
+                .max_by_key(|media| media.get("width").and_then(Value::as_u64).unwrap_or(0))?;
+            let url = image.get("url")?.as_str()?;
+            let extension = match image.get("type").and_then(Value::as_str) {
+                Some(mime) => mime.strip_prefix("image/")?.replace("jpeg", "jpg"),
+                None => url.rsplit('.').next()?.to_string(),
+            };
+            Some(Media::Image {
+                url: url.to_string(),
+                extension,
+            })
+        }
+        //Videos from other providers like youtube are only embedded, there is no file to load
+        "video" if block.get("provider").and_then(Value::as_str) == Some("tumblr") => {
+            let url = block.get("media")?.get("url")?.as_str()?;
+            Some(Media::Video {
+                url: url.to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use url::Url;
+
+    use crate::tumblr::{extract_media, find_post, parse_post_url, Media};
+
+    const POST: &str = include_str!("../../tests/fixtures/tumblr/post.html");
+
+    fn parse(url: &str) -> Option<(String, String)> {
+        parse_post_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_parse_post_urls() {
+        let expected = Some(("staff".to_string(), "713482013426".to_string()));
+        assert_eq!(parse("https://www.tumblr.com/staff/713482013426"), expected);
+        assert_eq!(
+            parse("https://tumblr.com/staff/713482013426/some-slug"),
+            expected
+        );
+        assert_eq!(
+            parse("https://staff.tumblr.com/post/713482013426/some-slug"),
+            expected
+        );
+        assert_eq!(parse("https://staff.tumblr.com/archive"), None);
+        assert_eq!(parse("https://www.tumblr.com/staff"), None);
+    }
+
+    #[test]
+    fn test_extract_every_media_in_order() {
+        let media = extract_media(POST, "713482013426").unwrap();
+
+        assert_eq!(
+            media,
+            vec![
+                Media::Image {
+                    url: "https://64.media.tumblr.com/abc/s2048x3072/original.jpg".into(),
+                    extension: "jpg".into(),
+                },
+                Media::Image {
+                    url: "https://64.media.tumblr.com/def/s540x810/dancing.gif".into(),
+                    extension: "gif".into(),
+                },
+                Media::Video {
+                    url: "https://va.media.tumblr.com/tumblr_xyz.mp4".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numeric_id_falls_back_to_id_string() {
+        let state = json!({
+            "posts": [{"id": 713482013426u64, "idString": "713482013426", "content": []}]
+        });
+        assert!(find_post(&state, "713482013426").is_some());
+        assert!(find_post(&state, "713482099999").is_none());
+    }
+
+    #[test]
+    fn test_text_post_has_no_media() {
+        let media = extract_media(POST, "713482099999").unwrap();
+        assert!(media.is_empty());
+    }
 }
@@ -1,39 +1,136 @@
 use std::format as f;
-use std::ops::Not;
 use std::path::PathBuf;
-use std::process::Stdio;
-use std::sync::OnceLock;
+use std::time::Duration;
 
-use serenity::model::channel::Message;
-use tokio::process::Command;
+use serenity::async_trait;
 use tracing::info;
-
-use tracing::log::error;
+use url::Url;
+use uuid::Uuid;
 
 use crate::loaderror::{LoadError, LoadResult};
-use crate::{create_working_dir, TEMP_DIR};
+use crate::{
+    create_working_dir, host_matches, mbyte_to_byte, ytdlp, AudioFormat, Downloader, LoadOptions,
+    TEMP_DIR,
+};
+
+/// Length of the clip we make from a link to a point in time like `?t=123`
+const URL_CLIP_SECS: u64 = 60;
+/// The size of a format is the size of the whole video, it says nothing about the size of a clip.
+/// Clips are sorted by resolution instead and this one usually fits.
+const CLIP_RESOLUTION: u32 = 480;
+
+pub struct Youtube;
+
+#[async_trait]
+impl Downloader for Youtube {
+    fn name(&self) -> &'static str {
+        "youtube"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        host_matches(url, "youtube.com") || host_matches(url, "youtu.be")
+    }
+
+    /// A link to a point in time is downloaded as a clip, which is not the same media
+    fn media_id(&self, url: &Url) -> Option<String> {
+        video_id(url).filter(|_| start_param(url).is_none())
+    }
+
+    async fn load(&self, url: &Url, options: &LoadOptions) -> LoadResult<Vec<PathBuf>> {
+        load(url, options).await.map(|path| vec![path])
+    }
+}
+
+/// The id of the video for `youtu.be/<id>`, `watch?v=<id>` and `/shorts|embed|live/<id>` links
+fn video_id(url: &Url) -> Option<String> {
+    let mut segments = url.path_segments()?;
+    let id = match url.host_str()? {
+        "youtu.be" => segments.next()?.to_string(),
+        _ => match segments.next()? {
+            "watch" => url
+                .query_pairs()
+                .find(|(key, _)| key == "v")
+                .map(|(_, id)| id.into_owned())?,
+            "shorts" | "embed" | "live" => segments.next()?.to_string(),
+            _ => return None,
+        },
+    };
+
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(id)
+}
+
+/// The point in time of `?t=` and of the `?start=` of embed links, zero counts as none
+fn start_param(url: &Url) -> Option<Duration> {
+    url.query_pairs()
+        .find(|(key, _)| key == "t" || key == "start")
+        .and_then(|(_, value)| parse_time_param(&value))
+        .filter(|start| !start.is_zero())
+}
+
+/// The `t` parameter is either plain seconds or written like `1h2m3s`
+fn parse_time_param(text: &str) -> Option<Duration> {
+    if let Ok(seconds) = text.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
+            }
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        seconds = number
+            .parse::<u64>()
+            .ok()?
+            .checked_mul(unit)?
+            .checked_add(seconds)?;
+        number.clear();
+    }
+    number.is_empty().then_some(Duration::from_secs(seconds))
+}
+
+/// Options with the clip of a `?t=` link, a range the user gave explicitly wins
+fn clip_of_url(url: &Url, options: &LoadOptions) -> LoadOptions {
+    let mut options = options.clone();
+    if let (false, Some(start)) = (options.is_clip(), start_param(url)) {
+        options.start = Some(start);
+        options.end = start.checked_add(Duration::from_secs(URL_CLIP_SECS));
+    }
+    options
+}
 
 //#[cfg(target_os = "linux")]
 //const YT_DL: &str = "vendors/yt-dlp_linux";
 //#[cfg(target_os = "macos")]
 //const YT_DL: &str = "vendors/yt-dlp_macos";
 
-static COMMAND: OnceLock<Command> = OnceLock::new();
-
 //We are allocating a dynamic PathBuf on the Heap, we could use lifetimes to
 // use a Path Object on the stack instead
-pub async fn load(url: &str, msg: &Message, max_filesize: u16) -> LoadResult<PathBuf> {
-    if url.contains("playlist") {
-        info!("{} is a playlist, we dont load it", &msg.content);
-        return Err(
-            "Your link is a Playlist, to prevent spamming of the Discord channel i wont load it"
+pub async fn load(url: &Url, options: &LoadOptions) -> LoadResult<PathBuf> {
+    if url.path().contains("playlist") {
+        info!("{} is a playlist, we dont load it", url);
+        return Err(LoadError::Unsupported {
+            platform: "YouTube",
+            reason: "Your link is a Playlist, to prevent spamming of the Discord channel i wont \
+                     load it"
                 .into(),
-        );
+        });
     }
 
-    let filename = msg.id.to_string();
-    let filename = filename.trim();
-    let downloaded_file = download_file(url, filename, max_filesize).await?;
+    let options = clip_of_url(url, options);
+    let filename = Uuid::new_v4().to_string();
+    let downloaded_file = download_file(url.as_str(), &filename, &options).await?;
 
     match downloaded_file.exists() {
         true => Ok(downloaded_file),
@@ -42,108 +139,186 @@ pub async fn load(url: &str, msg: &Message, max_filesize: u16) -> LoadResult<Pat
                 "File {:#?} does not exist, it was probably to large to download",
                 downloaded_file
             );
-            Err(f!(
-                "Your file is over the Limit of {}, please download it manually",
-                max_filesize
-            )
-            .into())
+            Err(LoadError::TooLarge {
+                size: None,
+                limit: mbyte_to_byte(options.max_filesize.into()),
+            })
         }
     }
 }
 
-async fn download_file(url: &str, filename: &str, max_filesize: u16) -> LoadResult<PathBuf> {
+async fn download_file(url: &str, filename: &str, options: &LoadOptions) -> LoadResult<PathBuf> {
     // Because i am changing the working dir of the Child it does not find the
     // yt-dlp_macos binary so i made the path ot the program also canonical
     // There may be a way better method to solve this problem
     let temp_dir = TEMP_DIR.get_or_try_init(create_working_dir)?;
-    let filename = f!("{}.mp4", filename);
-    // r#" -S "res:720" -o {}  --max-filesize {}"#,
-    // r#"-f "b[ext=mp4]" -S "filesize~7M" -o {}"#
-    //TODO: Maybe create a Folder in the temp dir ?
-
-    let cmd = f!(
-        r#"-f "b[ext=mp4]" -S "filesize~{}M" -o {} {}"#,
-        max_filesize - 1,
-        filename,
-        url
-    );
-    let args = shell_words::split(&cmd).unwrap();
-    //-f best[height=720]
-
-    //We set the working Dir to the tmp dir of the OS to not worry about deleting
-    //trash files generated by aborted downloads
-    let child_handle = Command::new("yt-dlp")
-        .args(args)
-        .stdout(Stdio::piped())
-        .current_dir(temp_dir)
-        .spawn()
-        .map_err(|os_error| {
-            LoadError::Error(f!("Invocation of yt-dlp failed with: {}", os_error).into())
-        })?;
-
-    //Ok so we need to use the Tokio Command module here, std::process::Command
-    //blocks the entire process
-    match child_handle.wait_with_output().await {
-        Ok(output) => {
-            if output.status.success().not() {
-                error!(
-                    "Output of Child Process stdout: {} \n stderr: {}",
-                    String::from_utf8_lossy(&output.stdout),
-                    String::from_utf8_lossy(&output.stderr),
-                );
-                return Err(f!(
-                    "Command yt-dlp {} failed with exit status {}",
-                    cmd,
-                    output.status
-                )
-                .into());
+    let extension = options.audio.map_or("mp4", AudioFormat::extension);
+
+    ytdlp::run_with_progress(
+        &ytdlp_args(url, filename, options),
+        options.progress.as_ref(),
+    )
+    .await?;
+    Ok(temp_dir.join(f!("{filename}.{extension}")))
+}
+
+/// `-S` sorts the formats so the best one below the size limit, and the resolution when one is
+/// given, comes first. For clips only the wanted section is downloaded. Audio is extracted from
+/// the best audio format below the size limit by yt-dlp, which needs ffmpeg and for opus files
+/// also mutagen.
+fn ytdlp_args(url: &str, filename: &str, options: &LoadOptions) -> Vec<String> {
+    let size_sort = f!("filesize~{}M", options.max_filesize.saturating_sub(1));
+    let mut args = match options.audio {
+        Some(format) => {
+            let mut args = vec!["-f".to_string(), "ba/b".to_string()];
+            //The size of the whole file says nothing about the size of a clip
+            if !options.is_clip() {
+                args.extend(["-S".to_string(), size_sort]);
+            }
+            args.extend([
+                "-x".to_string(),
+                "--audio-format".to_string(),
+                format.extension().to_string(),
+                "--embed-metadata".to_string(),
+                "--embed-thumbnail".to_string(),
+                "--convert-thumbnails".to_string(),
+                "jpg".to_string(),
+                //The extension changes when the audio is converted
+                "-o".to_string(),
+                f!("{filename}.%(ext)s"),
+            ]);
+            args
+        }
+        None => {
+            let mut sort = size_sort;
+            if options.is_clip() {
+                sort = f!("res:{}", options.max_resolution.unwrap_or(CLIP_RESOLUTION));
+            } else if let Some(height) = options.max_resolution {
+                sort = f!("res:{height},{sort}");
             }
+            vec![
+                "-f".to_string(),
+                "b[ext=mp4]".to_string(),
+                "-S".to_string(),
+                sort,
+                "-o".to_string(),
+                f!("{filename}.mp4"),
+            ]
         }
-        Err(err) => return Err(err.into()),
     };
-    Ok(temp_dir.join(filename))
+
+    if options.is_clip() {
+        let start = options.start.map_or(0, |start| start.as_secs());
+        let end = options
+            .end
+            .map_or("inf".to_string(), |end| end.as_secs().to_string());
+        args.push("--download-sections".to_string());
+        args.push(f!("*{start}-{end}"));
+    }
+    args.push(url.to_string());
+    args
 }
 
 #[cfg(test)]
 mod test {
-    use crate::youtube::download_file;
-    use std::fs;
-
-    #[tokio::test]
-    async fn test_download_file_full_video() -> Result<(), String> {
-        match download_file("https://www.youtube.com/shorts/B1j3yeHRKbY", "test1", 25).await {
-            Ok(file_path) => {
-                assert!(file_path.exists());
-                fs::remove_file(file_path).expect("Panicked while deleting File in test");
-            }
-            Err(err) => return Err(err.to_string()),
-        };
+    use crate::youtube::{clip_of_url, parse_time_param, video_id, ytdlp_args};
+    use crate::{AudioFormat, LoadOptions};
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn test_video_id() {
+        let id = |url: &str| video_id(&Url::parse(url).unwrap());
 
-        Ok(())
+        assert_eq!(
+            id("https://youtu.be/TK4N5W22Gts?t=3"),
+            Some("TK4N5W22Gts".into())
+        );
+        assert_eq!(
+            id("https://www.youtube.com/watch?v=TK4N5W22Gts&t=3"),
+            Some("TK4N5W22Gts".into())
+        );
+        assert_eq!(
+            id("https://www.youtube.com/shorts/B1j3yeHRKbY"),
+            Some("B1j3yeHRKbY".into())
+        );
+        assert_eq!(id("https://www.youtube.com/playlist?list=PL123"), None);
     }
 
-    #[tokio::test]
-    async fn test_download_youtube_shorts() -> Result<(), String> {
-        match download_file("https://www.youtube.com/watch?v=TK4N5W22Gts", "test2", 25).await {
-            Ok(file_path) => {
-                assert!(file_path.exists());
-                fs::remove_file(file_path).expect("Panicked while deleting File in test");
-            }
-            Err(err) => return Err(err.to_string()),
-        };
+    #[test]
+    fn test_ytdlp_args() {
+        let url = "https://youtu.be/UT5F9AXjwhg";
+        assert_eq!(
+            ytdlp_args(url, "a", &LoadOptions::new(25)).join(" "),
+            "-f b[ext=mp4] -S filesize~24M -o a.mp4 https://youtu.be/UT5F9AXjwhg"
+        );
+
+        let mut options = LoadOptions::new(8);
+        options.max_resolution = Some(720);
+        options.start = Some(Duration::from_secs(83));
+        assert_eq!(
+            ytdlp_args(url, "a", &options).join(" "),
+            "-f b[ext=mp4] -S res:720 -o a.mp4 --download-sections *83-inf \
+             https://youtu.be/UT5F9AXjwhg"
+        );
+
+        options.audio = Some(AudioFormat::Opus);
+        assert_eq!(
+            ytdlp_args(url, "a", &options).join(" "),
+            "-f ba/b -x --audio-format opus --embed-metadata --embed-thumbnail \
+             --convert-thumbnails jpg -o a.%(ext)s --download-sections *83-inf \
+             https://youtu.be/UT5F9AXjwhg"
+        );
 
-        Ok(())
+        options.start = None;
+        assert_eq!(
+            ytdlp_args(url, "a", &options).join(" "),
+            "-f ba/b -S filesize~7M -x --audio-format opus --embed-metadata --embed-thumbnail \
+             --convert-thumbnails jpg -o a.%(ext)s https://youtu.be/UT5F9AXjwhg"
+        );
     }
 
-    #[tokio::test]
-    async fn test_download_youtube_share_link() -> Result<(), String> {
-        match download_file("https://youtu.be/UT5F9AXjwhg", "test3", 25).await {
-            Ok(file_path) => {
-                assert!(file_path.exists());
-                fs::remove_file(file_path).expect("Panicked while deleting File in test");
-            }
-            Err(err) => return Err(err.to_string()),
+    #[test]
+    fn test_clip_of_url() {
+        let options = LoadOptions::new(8);
+        let clip = |url: &str, options: &LoadOptions| {
+            let options = clip_of_url(&Url::parse(url).unwrap(), options);
+            (
+                options.start.map(|start| start.as_secs()),
+                options.end.map(|end| end.as_secs()),
+            )
         };
-        Ok(())
+
+        assert_eq!(
+            clip("https://youtu.be/TK4N5W22Gts?t=123", &options),
+            (Some(123), Some(183))
+        );
+        assert_eq!(
+            clip(
+                "https://www.youtube.com/watch?v=TK4N5W22Gts&t=1m3s",
+                &options
+            ),
+            (Some(63), Some(123))
+        );
+        assert_eq!(
+            clip("https://www.youtube.com/watch?v=TK4N5W22Gts&t=0", &options),
+            (None, None)
+        );
+
+        //The range of the command or message wins over the link
+        let mut explicit = LoadOptions::new(8);
+        explicit.start = Some(Duration::from_secs(5));
+        explicit.end = Some(Duration::from_secs(10));
+        assert_eq!(
+            clip("https://youtu.be/TK4N5W22Gts?t=123", &explicit),
+            (Some(5), Some(10))
+        );
+
+        assert_eq!(parse_time_param("1h2m3s"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_time_param("45s"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_time_param("1m3"), None);
+        assert_eq!(parse_time_param("abc"), None);
+        assert_eq!(parse_time_param("99999999999999999999h"), None);
+        assert_eq!(parse_time_param("999999999999999999h"), None);
     }
 }
@@ -0,0 +1,214 @@
+use std::ops::Not;
+use std::process::{Output, Stdio};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tracing::error;
+
+use crate::loaderror::{LoadError, LoadResult};
+use crate::{create_working_dir, tool, Progress, TEMP_DIR};
+
+/// Runs yt-dlp with the working dir set to the tmp dir of the OS, so we dont have to worry
+/// about deleting trash files generated by aborted downloads. A non zero exit status is an error.
+pub(crate) async fn run(args: &[String]) -> LoadResult<Output> {
+    let output = spawn(args)?.wait_with_output().await?;
+    check(args, output)
+}
+
+/// Like [run] but yt-dlp prints its progress line by line and the percentage is handed to
+/// `progress`, without one it is just [run]
+pub(crate) async fn run_with_progress(
+    args: &[String],
+    progress: Option<&Progress>,
+) -> LoadResult<Output> {
+    let Some(progress) = progress else {
+        return run(args).await;
+    };
+    let args = ["--newline".to_string(), "--progress".to_string()]
+        .into_iter()
+        .chain(args.iter().cloned())
+        .collect::<Vec<String>>();
+    let mut child = spawn(&args)?;
+
+    //Both pipes are read at the same time, a full stderr pipe would block yt-dlp otherwise
+    let (Some(stdout), Some(mut stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return Err(LoadError::Internal("yt-dlp has no output pipes".into()));
+    };
+    let read_stdout = async {
+        let mut text = String::new();
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            if let Some(percent) = parse_progress(&line) {
+                progress.report(percent);
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+        Ok::<String, std::io::Error>(text)
+    };
+    let mut stderr_bytes = Vec::new();
+    let (stdout, _) = tokio::try_join!(read_stdout, stderr.read_to_end(&mut stderr_bytes))?;
+
+    let output = Output {
+        status: child.wait().await?,
+        stdout: stdout.into_bytes(),
+        stderr: stderr_bytes,
+    };
+    check(&args, output)
+}
+
+fn spawn(args: &[String]) -> LoadResult<Child> {
+    let temp_dir = TEMP_DIR.get_or_try_init(create_working_dir)?;
+
+    //Ok so we need to use the Tokio Command module here, std::process::Command
+    //blocks the entire process
+    Command::new(tool("yt-dlp"))
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .current_dir(temp_dir)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|os_error| {
+            LoadError::Internal(format!("Invocation of yt-dlp failed with: {}", os_error).into())
+        })
+}
+
+fn check(args: &[String], output: Output) -> LoadResult<Output> {
+    if output.status.success().not() {
+        error!(
+            "Command yt-dlp {} failed with exit status {}, stdout: {} \n stderr: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr),
+        );
+        return Err(classify(
+            &String::from_utf8_lossy(&output.stderr),
+            output.status.code(),
+        ));
+    }
+
+    Ok(output)
+}
+
+/// The percentage of a progress line like `[download]  42.3% of ~10.00MiB at 1.20MiB/s`
+fn parse_progress(line: &str) -> Option<u8> {
+    let percent = line
+        .strip_prefix("[download]")?
+        .split_whitespace()
+        .next()?
+        .strip_suffix('%')?
+        .parse::<f32>()
+        .ok()?;
+    Some(percent.clamp(0.0, 100.0) as u8)
+}
+
+/// yt-dlp only tells us what went wrong in its error messages, the ones the user can do
+/// something about get their own variant
+fn classify(stderr: &str, exit_code: Option<i32>) -> LoadError {
+    let message = stderr.to_lowercase();
+    let contains = |patterns: &[&str]| patterns.iter().any(|pattern| message.contains(pattern));
+
+    if contains(&["private video", "video is private", "login required"]) {
+        LoadError::Private
+    } else if contains(&["confirm your age", "age-restricted", "age restricted"]) {
+        LoadError::AgeRestricted
+    } else if contains(&["http error 429", "too many requests"]) {
+        LoadError::RateLimited { retry_after: None }
+    } else if contains(&["video unavailable", "http error 404", "has been removed"]) {
+        LoadError::NotFound
+    } else {
+        LoadError::ToolFailed {
+            tool: "yt-dlp",
+            exit_code,
+            stderr: stderr.trim().to_string(),
+        }
+    }
+}
+
+/// Errors of yt-dlp that go away when we try again, YouTube for example answers some requests
+/// with a 403 and extractors fail on half loaded pages
+pub(crate) fn is_hiccup(stderr: &str) -> bool {
+    let message = stderr.to_lowercase();
+    [
+        "http error 403",
+        "http error 5",
+        "timed out",
+        "connection reset",
+        "incompleteread",
+        "unable to download webpage",
+        "unable to extract",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::loaderror::LoadError;
+    use crate::ytdlp::{classify, is_hiccup, parse_progress};
+
+    #[test]
+    fn test_classify_errors() {
+        assert!(matches!(
+            classify(
+                "ERROR: [youtube] abc: Private video. Sign in if you've been granted access",
+                Some(1)
+            ),
+            LoadError::Private
+        ));
+        assert!(matches!(
+            classify("ERROR: [youtube] abc: Sign in to confirm your age", Some(1)),
+            LoadError::AgeRestricted
+        ));
+        assert!(matches!(
+            classify(
+                "ERROR: unable to download webpage: HTTP Error 429: Too Many Requests",
+                Some(1)
+            ),
+            LoadError::RateLimited { retry_after: None }
+        ));
+        assert!(matches!(
+            classify("ERROR: [youtube] abc: Video unavailable", Some(1)),
+            LoadError::NotFound
+        ));
+        assert!(matches!(
+            classify("ERROR: Postprocessing: ffprobe not found\n", Some(1)),
+            LoadError::ToolFailed {
+                tool: "yt-dlp",
+                exit_code: Some(1),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_hiccups_are_retryable() {
+        assert!(is_hiccup(
+            "ERROR: unable to download video data: HTTP Error 403: Forbidden"
+        ));
+        assert!(is_hiccup(
+            "ERROR: [youtube] abc: Unable to extract initial player response"
+        ));
+        assert!(!is_hiccup("ERROR: Postprocessing: ffprobe not found"));
+
+        let failed = classify("ERROR: Read timed out.", Some(1));
+        assert!(failed.is_retryable());
+        assert!(!classify("ERROR: [youtube] abc: Video unavailable", Some(1)).is_retryable());
+    }
+
+    #[test]
+    fn test_parse_progress() {
+        assert_eq!(
+            parse_progress("[download]  42.3% of ~  10.00MiB at    1.20MiB/s ETA 00:05"),
+            Some(42)
+        );
+        assert_eq!(
+            parse_progress("[download] 100% of   10.00MiB in 00:00:08"),
+            Some(100)
+        );
+        assert_eq!(parse_progress("[download] Destination: 1b2c.mp4"), None);
+        assert_eq!(parse_progress("[youtube] abc: Downloading webpage"), None);
+    }
+}
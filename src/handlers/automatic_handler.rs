@@ -1,23 +1,608 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use serenity::async_trait;
 use serenity::model::channel::Message;
-use serenity::model::prelude::{Channel, Mention, Ready, User};
+use serenity::model::guild::{Guild, PartialGuild};
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use serenity::model::interactions::Interaction;
+use serenity::model::prelude::Ready;
 use serenity::prelude::{Context, EventHandler};
 use tracing::{error, info, trace};
 use url::Url;
 
-use crate::handlers::{delete_file, send_debug_message, send_webhook_message};
-use crate::Config;
+use crate::config::RepostAction;
+use crate::handlers::feedback::{self, Stage};
+use crate::handlers::task::Task;
+use crate::handlers::webhooks::Webhooks;
+use crate::handlers::{
+    audio_only, batch_uploads, cache_key, incomplete_repost, max_upload_mb, read_context, repost,
+    send_debug_message, send_followup_message, slash_command, GuildTiers, TempFiles,
+};
+use crate::jobs::{Delivery, Job, JobQueue, PlatformLimits};
 use format as f;
-use social_loaders::loaderror::LoadError;
-use social_loaders::{UrlKind, DISCORD_MAX_FILE_SIZE_MB};
+use social_loaders::cache::{Cache, Cached};
+use social_loaders::loaderror::{LoadError, LoadResult};
+use social_loaders::phash::{self, RepostStore};
+use social_loaders::{
+    byte_to_mbyte, compress, mbyte_to_byte, Downloader, LoadOptions, Notes, Registry,
+};
+
+pub struct AutomaticDownloader {
+    queue: JobQueue,
+    /// Only tells which links of a message we can download, the [Processor] loads them
+    registry: Registry,
+}
 
-pub struct AutomaticDownloader;
+impl AutomaticDownloader {
+    pub fn new(queue: JobQueue, registry: Registry) -> Self {
+        AutomaticDownloader { queue, registry }
+    }
+}
 
-pub struct Task {
-    sender: User,
-    channel: Channel,
-    mentions: Vec<Mention>,
-    url: Vec<Url>,
+/// Does the actual work of a [Job]: downloads every link, makes the files fit and reposts them
+pub struct Processor {
+    registry: Registry,
+    limits: PlatformLimits,
+    cache: Option<Cache>,
+    reposts: Option<RepostStore>,
+    webhooks: Webhooks,
+}
+
+/// What we got for a single url
+enum Loaded {
+    Files {
+        paths: Vec<PathBuf>,
+        cache_key: Option<String>,
+        /// What the downloader wants the user to know, shown below the repost
+        notes: Vec<String>,
+        /// Files of the post the downloader could not load, the others are in `paths`
+        failed: Vec<LoadError>,
+    },
+    /// CDN urls and hashes of an earlier upload of the same media
+    Cached(Cached),
+}
+
+impl Processor {
+    pub fn new(
+        registry: Registry,
+        limits: PlatformLimits,
+        cache: Option<Cache>,
+        reposts: Option<RepostStore>,
+    ) -> Self {
+        Processor {
+            registry,
+            limits,
+            cache,
+            reposts,
+            webhooks: Webhooks::default(),
+        }
+    }
+
+    /// Hashes every file when we look for reposts at all. The hashes are kept with the cache
+    /// entry of the file, so the same media is still recognized when it comes from the cache.
+    async fn hash_files(&self, files: &[(PathBuf, u64)]) -> HashMap<PathBuf, Vec<u64>> {
+        let mut hashes = HashMap::new();
+        if self.reposts.is_none() {
+            return hashes;
+        }
+        for (path, _) in files {
+            hashes.insert(path.clone(), phash::hash_file(path).await);
+        }
+        hashes
+    }
+
+    /// Link to an earlier post in the guild with the same media
+    fn find_repost(&self, task: &Task, hashes: &[u64]) -> Option<String> {
+        let (Some(reposts), Some(guild_id)) = (&self.reposts, task.guild_id) else {
+            return None;
+        };
+        reposts.find(guild_id.0, hashes)
+    }
+
+    /// Runs the downloader for the url, problems are reported with [report] and `None` is
+    /// returned so the other urls of the message can still be handled
+    async fn load(&self, job: &Job, url: &Url, stage: &mut Stage<'_>) -> Option<Loaded> {
+        let config = &job.config;
+        let Some(downloader) = self.registry.find(url) else {
+            info!("No downloader found for {url}");
+            answer_command(job, &f!("I dont know how to download {url}")).await;
+            return None;
+        };
+        if !config.downloaders.enabled(downloader.name()) {
+            info!(
+                "Downloader {} is turned off, ignoring {url}",
+                downloader.name()
+            );
+            answer_command(
+                job,
+                &f!("Downloads from {} are turned off", downloader.name()),
+            )
+            .await;
+            return None;
+        }
+
+        let mut options = job.options.clone();
+        if let Some(clip) = job.task.clips.get(url) {
+            options.start = Some(clip.start);
+            options.end = Some(clip.end);
+        }
+
+        let cache_key = cache_key(downloader.name(), downloader.media_id(url), &options);
+        if let Some(cached) = cache_key
+            .as_deref()
+            .and_then(|key| self.cache.as_ref()?.get(key))
+        {
+            info!(
+                "{url} was uploaded before, reusing {} attachments",
+                cached.attachment_urls.len()
+            );
+            return Some(Loaded::Cached(cached));
+        }
+
+        //The progress is over when the download is, that is when the options are dropped
+        let (progress, receiver) = feedback::progress(job).unzip();
+        options.progress = progress;
+        let notes = Notes::default();
+        options.notes = Some(notes.clone());
+        let loading = async move {
+            self.load_with_retries(job, downloader, url, &options, stage)
+                .await
+        };
+        let status = async {
+            match receiver {
+                Some(receiver) => feedback::status_message(job, receiver).await,
+                None => None,
+            }
+        };
+        let (result, status) = tokio::join!(loading, status);
+        if let Some(status) = status {
+            let _ = status.delete(&job.http).await;
+        }
+
+        match result {
+            Ok(paths) => {
+                let (notes, failed) = notes.take();
+                //A compromise like a video without sound or a part of a post is not what
+                //anyone asked for
+                let cache_key = cache_key.filter(|_| notes.is_empty() && failed.is_empty());
+                Some(Loaded::Files {
+                    paths,
+                    cache_key,
+                    notes,
+                    failed,
+                })
+            }
+            Err(LoadError::Ignore(reason)) => {
+                info!("Url {url} rejected. Reason: {reason}");
+                answer_command(job, &reason).await;
+                None
+            }
+            Err(err) => {
+                match err.is_user_facing() {
+                    true => info!("Url {url} rejected with {}: {err}", err.code()),
+                    false => error!(
+                        "Trying to load file from url {url} resulted in {}: {err}",
+                        err.code()
+                    ),
+                }
+                report(job, &err).await;
+                None
+            }
+        }
+    }
+
+    /// Runs the downloader and tries again as long as the retry policy of the platform allows
+    async fn load_with_retries(
+        &self,
+        job: &Job,
+        downloader: &dyn Downloader,
+        url: &Url,
+        options: &LoadOptions,
+        stage: &mut Stage<'_>,
+    ) -> LoadResult<Vec<PathBuf>> {
+        //The slot is kept while we wait for a retry, that way we dont hammer a platform that
+        //has a hiccup with the other downloads
+        let _slot = self.limits.acquire(downloader.name()).await;
+        let policy = job.config.retry.policy(downloader.name());
+        let mut attempt = 1;
+        loop {
+            stage.set(feedback::DOWNLOADING).await;
+            let err = match downloader.load(url, options).await {
+                Err(err) => err,
+                loaded => return loaded,
+            };
+            let Some(delay) = policy.delay(attempt, &err) else {
+                return Err(err);
+            };
+            info!(
+                "Attempt {attempt} to load {url} failed with {}: {err}, trying again in {}ms",
+                err.code(),
+                delay.as_millis()
+            );
+            stage.set(feedback::WAITING).await;
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    pub async fn run(&self, job: Job) {
+        let mut stage = Stage::queued(&job);
+        self.process(&job, &mut stage).await;
+        stage.clear().await;
+    }
+
+    async fn process(&self, job: &Job, stage: &mut Stage<'_>) {
+        let config = &job.config;
+        let task = &job.task;
+        let max_upload_mb = job.options.max_filesize;
+
+        let mut handled_urls = Vec::new();
+        let mut downloaded_file_paths = Vec::new();
+        //CDN urls of earlier uploads together with the hashes of the attachment
+        let mut cached = Vec::new();
+        let mut notes = Vec::new();
+        let mut failed = Vec::new();
+        //Removed when the job is done, also when it is cancelled in the middle of it
+        let mut temp_files = TempFiles::default();
+        //The cache entry every downloaded file belongs to, files of urls without a media id
+        //are not cached
+        let mut cache_keys = HashMap::new();
+        for url in &task.urls {
+            match self.load(job, url, stage).await {
+                Some(Loaded::Files {
+                    paths,
+                    cache_key,
+                    notes: url_notes,
+                    failed: url_failed,
+                }) => {
+                    notes.extend(url_notes);
+                    failed.extend(url_failed);
+                    if let Some(key) = cache_key {
+                        for path in &paths {
+                            cache_keys.insert(path.clone(), key.clone());
+                        }
+                    }
+                    for path in &paths {
+                        temp_files.track(path.clone());
+                    }
+                    downloaded_file_paths.extend(paths);
+                }
+                Some(Loaded::Cached(Cached {
+                    attachment_urls,
+                    mut hashes,
+                })) => {
+                    hashes.resize(attachment_urls.len(), Vec::new());
+                    cached.extend(attachment_urls.into_iter().zip(hashes));
+                }
+                None => continue,
+            }
+            handled_urls.push(url.clone());
+        }
+        if handled_urls.is_empty() {
+            return;
+        }
+
+        // Validate that file can be sent:
+        // - No more than 25MB -> Calculate size in mb - We dont care about rounding down,
+        // as long as we get 24 we can send it to Discord
+        let mut files = Vec::with_capacity(downloaded_file_paths.len());
+        for path in &downloaded_file_paths {
+            match path.metadata() {
+                Ok(metadata) => files.push((path.clone(), metadata.len())),
+                Err(_) => {
+                    error!(
+                        "File Metadata result returned err, for {}, skipping it",
+                        path.to_string_lossy()
+                    );
+                }
+            };
+        }
+        if files.is_empty() && cached.is_empty() {
+            let err = LoadError::Internal("the downloaded files are gone".into());
+            error!("Message {} is not posted: {err}", job.message_id);
+            report(job, &err).await;
+            return;
+        }
+
+        //TODO: Stupid into Conversion from u16 to u64 that is only needed cause i made the const a u16
+        let max_upload_size = mbyte_to_byte(max_upload_mb.into());
+
+        //Videos that are too large get a second chance by re-encoding them with a lower bitrate
+        if config.compression.enabled {
+            let max_processing_time = Duration::from_secs(config.compression.max_processing_secs);
+            for (path, size) in files.iter_mut() {
+                if *size < max_upload_size || !compress::is_video(path) {
+                    continue;
+                }
+                stage.set(feedback::TRANSCODING).await;
+                match compress::compress_to_fit(path, max_upload_size - 1, max_processing_time)
+                    .await
+                {
+                    Ok(compressed) => {
+                        temp_files.track(compressed.clone());
+                        if let Some(key) = cache_keys.remove(path) {
+                            cache_keys.insert(compressed.clone(), key);
+                        }
+                        *size = compressed.metadata().map_or(u64::MAX, |m| m.len());
+                        *path = compressed;
+                    }
+                    Err(err) => info!("Could not compress {}: {err}", path.display()),
+                }
+            }
+        }
+        //Discord blurs attachments whose name starts with SPOILER_ and links between ||
+        if job.spoiler {
+            for (path, _) in files.iter_mut() {
+                let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+                    continue;
+                };
+                let spoiler = path.with_file_name(f!("SPOILER_{name}"));
+                if fs::rename(&*path, &spoiler).is_ok() {
+                    temp_files.track(spoiler.clone());
+                    if let Some(key) = cache_keys.remove(path) {
+                        cache_keys.insert(spoiler.clone(), key);
+                    }
+                    *path = spoiler;
+                }
+            }
+            for (url, _) in cached.iter_mut() {
+                *url = f!("||{url}||");
+            }
+        }
+        let repost_action = config
+            .channel_settings
+            .get(&task.channel_id.to_string())
+            .map(|settings| settings.repost_action)
+            .unwrap_or_default();
+        let file_hashes = self.hash_files(&files).await;
+        //Every file is looked up on its own, a post that shares one image with an earlier post
+        //is only a repost of that image. Somebody using /download asked for exactly this file,
+        //we only look for reposts in the messages we pick up ourselves.
+        let is_webhook = matches!(job.delivery, Delivery::Webhook);
+        let reposted_files = files
+            .iter()
+            .filter(|_| is_webhook)
+            .filter_map(|(path, _)| {
+                let original = self.find_repost(task, file_hashes.get(path)?)?;
+                Some((path.clone(), original))
+            })
+            .collect::<HashMap<PathBuf, String>>();
+        let reposted_cached = cached
+            .iter()
+            .filter(|_| is_webhook)
+            .filter_map(|(url, hashes)| Some((url.clone(), self.find_repost(task, hashes)?)))
+            .collect::<HashMap<String, String>>();
+        let mut originals = reposted_files
+            .values()
+            .chain(reposted_cached.values())
+            .cloned()
+            .collect::<Vec<String>>();
+        originals.sort();
+        originals.dedup();
+        let originals = originals.join(" ");
+        //Only the new files are remembered, the others link to their first post
+        let new_hashes = files
+            .iter()
+            .filter(|(path, _)| !reposted_files.contains_key(path))
+            .filter_map(|(path, _)| file_hashes.get(path))
+            .chain(
+                cached
+                    .iter()
+                    .filter(|(url, _)| !reposted_cached.contains_key(url))
+                    .map(|(_, hashes)| hashes),
+            )
+            .flatten()
+            .copied()
+            .collect::<Vec<u64>>();
+        if !originals.is_empty() && repost_action == RepostAction::Block {
+            info!(
+                "{} files of message {} are reposts of {originals}, blocking them",
+                reposted_files.len() + reposted_cached.len(),
+                job.message_id
+            );
+            files.retain(|(path, _)| !reposted_files.contains_key(path));
+            cached.retain(|(url, _)| !reposted_cached.contains_key(url));
+            if files.is_empty() && cached.is_empty() {
+                let _ = task
+                    .channel_id
+                    .send_message(&job.http, |m| {
+                        m.content(f!("This was already posted here: {originals}"))
+                            .reference_message((task.channel_id, job.message_id))
+                    })
+                    .await;
+                return;
+            }
+        }
+
+        let (batches, too_large) = batch_uploads(files, max_upload_size);
+        for (path, size) in &too_large {
+            let size_in_mb = byte_to_mbyte(*size);
+            info!("{} is {size_in_mb}MB large, skipping it", path.display());
+        }
+        //A post we could only upload partly is not cached, the next time it might fit
+        for (path, _) in &too_large {
+            if let Some(key) = cache_keys.get(path).cloned() {
+                cache_keys.retain(|_, other| *other != key);
+            }
+        }
+        if batches.is_empty() && cached.is_empty() {
+            let err = LoadError::TooLarge {
+                size: too_large.iter().map(|(_, size)| *size).max(),
+                limit: max_upload_size,
+            };
+            info!("Message {} is not posted: {err}", job.message_id);
+            report(job, &err).await;
+            return;
+        };
+
+        //Sending the Files to Webhook, one message for every batch. What the user wrote, the
+        //links of cached media and the notes of the downloaders go with the first one, when
+        //everything was cached that is the only message
+        let links = cached
+            .iter()
+            .map(|(url, _)| url.clone())
+            .chain(notes.iter().map(|note| f!("*{note}*")))
+            .collect::<Vec<String>>();
+        let content = task.repost_content(&handled_urls, &links);
+        let batches = match batches.is_empty() {
+            true => vec![Vec::new()],
+            false => batches,
+        };
+        let mut uploaded: HashMap<String, (Vec<String>, Vec<Vec<u64>>)> = HashMap::new();
+        let mut first_message = None;
+        let mut upload_error = None;
+        for (i, batch) in batches.iter().enumerate() {
+            let content = if i == 0 { &content } else { "" };
+            let result = match &job.delivery {
+                Delivery::Webhook => {
+                    repost(
+                        task,
+                        &job.http,
+                        &self.webhooks,
+                        config
+                            .channels_listening
+                            .get(&task.channel_id.to_string())
+                            .and_then(Option::as_deref),
+                        batch,
+                        content,
+                        config.mentions,
+                    )
+                    .await
+                }
+                Delivery::Interaction(command) => {
+                    send_followup_message(command, &job.http, batch, content).await
+                }
+            };
+            let message = match result {
+                Ok(message) => message,
+                Err(err) => {
+                    upload_error = Some(err);
+                    break;
+                }
+            };
+
+            for attachment in &message.attachments {
+                let path = batch
+                    .iter()
+                    .find(|path| path.file_name() == Some(OsStr::new(&attachment.filename)));
+                let Some((path, key)) = path.and_then(|path| Some((path, cache_keys.get(path)?)))
+                else {
+                    continue;
+                };
+                let (urls, hashes) = uploaded.entry(key.clone()).or_default();
+                urls.push(attachment.url.clone());
+                hashes.push(file_hashes.get(path).cloned().unwrap_or_default());
+            }
+            if first_message.is_none() {
+                first_message = Some(message);
+            }
+        }
+        if let Some(cache) = &self.cache {
+            for (key, (urls, hashes)) in uploaded {
+                cache.insert(key, urls, hashes);
+            }
+        }
+
+        //The webhook might post into another channel than the one the message came from
+        if let Some(posted) = &first_message {
+            if let (true, Some(reposts), Some(guild_id)) =
+                (is_webhook, &self.reposts, task.guild_id)
+            {
+                let link = posted.id.link(posted.channel_id, task.guild_id);
+                reposts.insert(guild_id.0, new_hashes, link);
+            }
+            if !originals.is_empty() {
+                info!("Message {} is a repost of {originals}", job.message_id);
+                if repost_action == RepostAction::Mark {
+                    let _ = posted
+                        .channel_id
+                        .create_reaction(&job.http, posted.id, '🔁')
+                        .await;
+                }
+                let reply = match repost_action {
+                    RepostAction::Block => f!("The rest was already posted here: {originals}"),
+                    _ => f!("Repost! The original is here: {originals}"),
+                };
+                let _ = posted
+                    .channel_id
+                    .send_message(&job.http, |m| m.content(reply).reference_message(posted))
+                    .await;
+            }
+        }
+
+        //The original stays up unless every file made it, otherwise the post would be lost
+        if let Some(err) = incomplete_repost(upload_error, failed, &too_large, max_upload_size) {
+            match err.is_user_facing() {
+                true => info!("Message {} is not fully reposted: {err}", job.message_id),
+                false => error!("Message {} is not reposted: {err}", job.message_id),
+            }
+            report(job, &err).await;
+            return;
+        }
+        if let Delivery::Webhook = job.delivery {
+            let _ = task
+                .channel_id
+                .delete_message(&job.http, job.message_id)
+                .await;
+            stage.forget();
+        }
+    }
+}
+
+/// Tells the user why something could not be posted. A message gets [FAILED](feedback::FAILED)
+/// with a reaction for the reason and a reply, our own problems also go to the debug channel.
+/// A `/download` command gets it as the answer.
+async fn report(job: &Job, err: &LoadError) {
+    let text = job.config.error_message(err);
+    let Delivery::Webhook = job.delivery else {
+        return answer_command(job, &text).await;
+    };
+
+    let task = &job.task;
+    if job.config.feedback.reactions {
+        for emoji in [Some(feedback::FAILED), reason(err)].into_iter().flatten() {
+            feedback::react(&job.http, task.channel_id, job.message_id, emoji).await;
+        }
+    }
+    let _ = task
+        .channel_id
+        .send_message(&job.http, |m| {
+            m.content(text)
+                .reference_message((task.channel_id, job.message_id))
+        })
+        .await;
+    if !err.is_user_facing() {
+        let detail = f!("[{}] Message {}: {err}", err.code(), job.message_id);
+        send_debug_message(&job.http, &detail, job.config.debug, &task.sender).await
+    }
+}
+
+/// Lets the user see at a glance why their message failed, our own problems have no reason
+fn reason(err: &LoadError) -> Option<&'static str> {
+    match err {
+        LoadError::TooLarge { .. } => Some("📦"),
+        LoadError::Unsupported { .. } => Some("🚫"),
+        LoadError::NotFound => Some("❓"),
+        LoadError::Private => Some("🔒"),
+        LoadError::AgeRestricted => Some("🔞"),
+        LoadError::RateLimited { .. } => Some("🐢"),
+        LoadError::Ignore(_)
+        | LoadError::ToolFailed { .. }
+        | LoadError::Network(_)
+        | LoadError::Internal(_) => None,
+    }
+}
+
+/// Like [report] but only for `/download`, links in messages that we can not handle are not
+/// worth a message in the debug channel
+async fn answer_command(job: &Job, text: &str) {
+    if let Delivery::Interaction(command) = &job.delivery {
+        let _ = send_followup_message(command, &job.http, &[], text).await;
+    }
 }
 
 #[async_trait]
@@ -29,18 +614,19 @@ impl EventHandler for AutomaticDownloader {
     // events can be dispatched simultaneously.
     async fn message(&self, ctx: Context, msg: Message) {
         //Read context Data first
-        let data = ctx.data.read().await;
-        let config = data
-            .get::<Config>()
-            .expect("Expected Config struct in ContextData");
+        let (config, guild_tier) = read_context(&ctx, msg.guild_id).await;
 
         //If the bot is the author of the user we end here
         let Ok(bot) = ctx.http.get_current_user().await else {
-            error!("No user found in context with message {} from {}", msg.id, msg.author);
+            error!(
+                "No user found in context with message {} from {}",
+                msg.id, msg.author
+            );
             return;
         };
 
-        if bot.id == msg.author.id {
+        //Our reposts come from a webhook, they still contain the links we could not load
+        if bot.id == msg.author.id || msg.webhook_id.is_some() {
             return;
         }
 
@@ -60,112 +646,75 @@ impl EventHandler for AutomaticDownloader {
             msg.author, msg.id, msg.content
         );
 
-        for x in msg.content.lines() {
-            let parsed = Url::parse(x);
+        let task = Task::from_message(&msg, config.max_urls_per_message, &self.registry);
+        if task.urls.is_empty() {
+            info!(
+                "Message content: {} - Does not contain a url i can work with so we end",
+                msg.content
+            );
+            return;
         }
 
-        //If something is embedded than we might have to do something if not ignore message and end
-        if msg.embeds.is_empty() {
-            info!("Nothing is embedded but maybe it did not load fast enough so lets make some extra checks");
-            if !(msg.content.contains("youtube")
-                || msg.content.contains("youtu.be")
-                || msg.content.contains("reddit"))
-            {
-                info!(
-                    "Message content: {} - Does not seem to be a url i can work with so we end",
-                    msg.content
-                );
-                return;
-            }
-            info!("ok its a youtube or reddit link, lets investigate further");
+        let mut options = LoadOptions::new(max_upload_mb(&config, msg.channel_id, guild_tier));
+        options.audio = audio_only(&config, msg.channel_id).then_some(config.audio_format);
+        options.compress = config.compression.enabled;
+        //Before it is queued, the job might already be running when the reaction is there
+        let reactions = config.feedback.reactions;
+        if reactions {
+            feedback::react(&ctx.http, msg.channel_id, msg.id, feedback::QUEUED).await;
         }
-
-        let url = match msg.embeds.is_empty() {
-            false => msg.embeds.get(0).unwrap().url.as_ref().unwrap(),
-            true => &msg.content,
+        let job = Job {
+            message_id: msg.id,
+            task,
+            config,
+            options,
+            spoiler: false,
+            delivery: Delivery::Webhook,
+            http: ctx.http.clone(),
         };
-        let downloaded_file_path = {
-            //TODO: clone should not be here
-            let url_kind = {
-                if url.contains("reddit") && config.downloaders.reddit {
-                    UrlKind::Reddit(url.clone())
-                } else if url.contains("youtube") && config.downloaders.youtube {
-                    UrlKind::Youtube(url.clone())
-                } else {
-                    return;
-                }
-            };
-
-            match url_kind.load(&msg, DISCORD_MAX_FILE_SIZE_MB).await {
-                Ok(path) => path,
-                Err(LoadError::Ignore(reason)) => {
-                    info!("Url {url} rejected. Reason: {reason}");
-                    return;
-                }
-                Err(LoadError::Rejected(message)) => {
-                    info!("Url {url} rejected. Reason: {message}");
-                    send_debug_message(&ctx, &message, config.debug, &msg.author).await;
-                    return;
-                }
-                Err(LoadError::Error(e)) => {
-                    error!("Trying to load file from url {url} resulted in err: {e}");
-                    let message = f!(
-                        "Internal System Error: User: {} MessageID: {} Url: {}",
-                        msg.author,
-                        msg.id,
-                        url
-                    );
-                    send_debug_message(&ctx, &message, config.debug, &msg.author).await;
-                    return;
-                }
+        if let Err(err) = self.queue.enqueue(job) {
+            info!("Message {} is not downloaded: {err:?}", msg.id);
+            if reactions {
+                feedback::unreact(&ctx.http, msg.channel_id, msg.id, feedback::QUEUED).await;
             }
-        };
-
-        // Validate that file can be sent:
-        // - No more than 25MB -> Calculate size in mb - We dont care about rounding down,
-        // as long as we get 24 we can send it to Discord
-        let size_in_mb = match downloaded_file_path.metadata() {
-            Ok(metadata) => (metadata.len() / 1024) / 1024,
-            Err(_) => {
-                error!(
-                    "File Metadata result returned err, for {} from: {}",
-                    downloaded_file_path.to_string_lossy(),
-                    url
-                );
-                return;
-            }
-        };
+        }
+    }
 
-        //TODO: Stupid into Conversion from u16 to u64 that is only needed cause i made the const a u16
-        if size_in_mb >= DISCORD_MAX_FILE_SIZE_MB.into() {
-            send_debug_message(
-                &ctx,
-                &f!(
-                    "The File is {size_in_mb}MB large, limit is {}MB so i cant post it",
-                    DISCORD_MAX_FILE_SIZE_MB
-                ),
-                config.debug,
-                &msg.author,
-            )
-            .await;
-            return;
-        };
+    async fn message_delete(
+        &self,
+        _ctx: Context,
+        _channel_id: ChannelId,
+        message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        //Our own delete after the upload ends up here too, by then the job is already done
+        if self.queue.cancel(message_id) {
+            info!("Message {message_id} was deleted, cancelled its download");
+        }
+    }
 
-        //TODO: Could not send Webhook error handling
-        //Sending the File to Webhook
-        send_webhook_message(
-            &msg,
-            config.channels_listening.get(&channel_id).unwrap(),
-            &downloaded_file_path,
-        )
-        .await;
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        if let Interaction::ApplicationCommand(command) = interaction {
+            slash_command::handle(&ctx, command, &self.queue).await;
+        }
+    }
 
-        let _msg = msg.channel_id.send_message(&ctx.http, |m| {
-            m.content(&msg.author.name).add_file(&downloaded_file_path)
-        });
+    async fn guild_create(&self, ctx: Context, guild: Guild) {
+        let mut data = ctx.data.write().await;
+        data.entry::<GuildTiers>()
+            .or_default()
+            .insert(guild.id, guild.premium_tier);
+    }
 
-        let _ = msg.delete(&ctx.http).await;
-        delete_file(&downloaded_file_path).await;
+    async fn guild_update(&self, ctx: Context, guild: PartialGuild) {
+        info!(
+            "Guild {} now has boost tier {:?}",
+            guild.id, guild.premium_tier
+        );
+        let mut data = ctx.data.write().await;
+        data.entry::<GuildTiers>()
+            .or_default()
+            .insert(guild.id, guild.premium_tier);
     }
 
     // Set a handler to be called on the `ready` event. This is called when a
@@ -173,8 +722,14 @@ impl EventHandler for AutomaticDownloader {
     // contains data like the current user's guild Ids, current user data,
     // private channels, and more.
     //
-    // In this case, just print what the current user's username is.
-    async fn ready(&self, _: Context, ready: Ready) {
+    // In this case, print what the current user's username is and register our commands.
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} with automatic_handler is connected!", ready.user.name);
+        if let Err(err) = slash_command::register(&ctx.http).await {
+            error!(
+                "Could not register the /{} command: {err}",
+                slash_command::NAME
+            );
+        }
     }
 }
@@ -0,0 +1,140 @@
+use std::time::{Duration, Instant};
+
+use serenity::http::Http;
+use serenity::model::channel::{Message, ReactionType};
+use serenity::model::id::{ChannelId, MessageId};
+use social_loaders::Progress;
+use tokio::sync::watch;
+
+use crate::jobs::{Delivery, Job};
+use format as f;
+
+pub const QUEUED: &str = "👀";
+pub const DOWNLOADING: &str = "⬇️";
+/// A download failed and waits to be tried again
+pub const WAITING: &str = "⏳";
+pub const TRANSCODING: &str = "⚙️";
+pub const FAILED: &str = "❌";
+
+/// Discord only lets us edit a message a few times in 5 seconds
+const EDIT_INTERVAL: Duration = Duration::from_secs(3);
+
+pub async fn react(http: &Http, channel_id: ChannelId, message_id: MessageId, emoji: &str) {
+    let _ = channel_id
+        .create_reaction(http, message_id, ReactionType::Unicode(emoji.to_string()))
+        .await;
+}
+
+/// Removes a reaction of the bot
+pub async fn unreact(http: &Http, channel_id: ChannelId, message_id: MessageId, emoji: &str) {
+    let _ = channel_id
+        .delete_reaction(
+            http,
+            message_id,
+            None,
+            ReactionType::Unicode(emoji.to_string()),
+        )
+        .await;
+}
+
+/// The reaction that shows what the job of a message is doing right now, a message only ever
+/// has one of them. `/download` shows that it is thinking instead.
+pub struct Stage<'a> {
+    job: &'a Job,
+    current: Option<&'static str>,
+}
+
+impl<'a> Stage<'a> {
+    /// The message got [QUEUED] when it was put into the queue
+    pub fn queued(job: &'a Job) -> Self {
+        Stage {
+            job,
+            current: enabled(job).then_some(QUEUED),
+        }
+    }
+
+    pub async fn set(&mut self, stage: &'static str) {
+        if !enabled(self.job) || self.current == Some(stage) {
+            return;
+        }
+        self.clear().await;
+        react(
+            &self.job.http,
+            self.job.task.channel_id,
+            self.job.message_id,
+            stage,
+        )
+        .await;
+        self.current = Some(stage);
+    }
+
+    pub async fn clear(&mut self) {
+        if let Some(current) = self.current.take() {
+            unreact(
+                &self.job.http,
+                self.job.task.channel_id,
+                self.job.message_id,
+                current,
+            )
+            .await;
+        }
+    }
+
+    /// The message was deleted together with its reactions
+    pub fn forget(&mut self) {
+        self.current = None;
+    }
+}
+
+fn enabled(job: &Job) -> bool {
+    matches!(job.delivery, Delivery::Webhook) && job.config.feedback.reactions
+}
+
+/// Where the downloader reports its progress to, `None` when the job gets no status message.
+/// The receiver sees the end of the download when the [Progress] is dropped.
+pub fn progress(job: &Job) -> Option<(Progress, watch::Receiver<u8>)> {
+    if !matches!(job.delivery, Delivery::Webhook) || !job.config.feedback.status_message {
+        return None;
+    }
+    let (sender, receiver) = watch::channel(0);
+    let progress = Progress::new(move |percent| {
+        let _ = sender.send(percent);
+    });
+    Some((progress, receiver))
+}
+
+/// Replies to the message with the progress once the download takes longer than
+/// `status_after_secs` and keeps the reply up to date until the download is done. Returns the
+/// reply so it can be deleted.
+pub async fn status_message(job: &Job, mut receiver: watch::Receiver<u8>) -> Option<Message> {
+    let started = Instant::now();
+    let delay = Duration::from_secs(job.config.feedback.status_after_secs);
+    let mut message: Option<Message> = None;
+    let mut last_edit: Option<Instant> = None;
+
+    while receiver.changed().await.is_ok() {
+        let percent = *receiver.borrow_and_update();
+        if started.elapsed() < delay || last_edit.is_some_and(|at| at.elapsed() < EDIT_INTERVAL) {
+            continue;
+        }
+        let text = f!("Downloading... {percent}%");
+        match &mut message {
+            Some(message) => {
+                let _ = message.edit(&job.http, |m| m.content(&text)).await;
+            }
+            None => {
+                message = job
+                    .task
+                    .channel_id
+                    .send_message(&job.http, |m| {
+                        m.content(&text)
+                            .reference_message((job.task.channel_id, job.message_id))
+                    })
+                    .await
+                    .ok();
+            }
+        }
+        last_edit = Some(Instant::now());
+    }
+    message
+}
@@ -1,54 +1,514 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::fs;
+use std::io::ErrorKind;
 use std::path::PathBuf;
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
 
-use serde_json::json;
+use reqwest::StatusCode;
+use serenity::builder::{CreateAllowedMentions, ParseValue};
 use serenity::http::Http;
 use serenity::model::channel::Message;
-use serenity::model::id::ChannelId;
-use serenity::model::prelude::{User, Webhook};
-use serenity::prelude::Context;
+use serenity::model::guild::PremiumTier;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use serenity::model::interactions::application_command::ApplicationCommandInteraction;
+use serenity::model::prelude::{Mention, User};
+use serenity::model::webhook::Webhook;
+use serenity::prelude::{Context, TypeMapKey};
 use serenity::utils::MessageBuilder;
+use social_loaders::cache;
+use social_loaders::loaderror::LoadError;
+use social_loaders::{LoadOptions, DISCORD_MAX_ATTACHMENTS, DISCORD_MAX_FILE_SIZE_MB};
 use tracing::error;
 use tracing::log::info;
 
+use crate::config::{Config, MentionPolicy};
+use crate::handlers::task::Task;
+use crate::handlers::webhooks::Webhooks;
+
 pub mod automatic_handler;
+pub mod feedback;
+pub mod slash_command;
+pub mod task;
+pub mod webhooks;
+
+/// Boost tier of every guild the bot is in. We dont use the serenity cache so the handler keeps
+/// this up to date from the `guild_create` and `guild_update` gateway events.
+pub struct GuildTiers;
+
+impl TypeMapKey for GuildTiers {
+    type Value = HashMap<GuildId, PremiumTier>;
+}
+
+/// Largest file in MB a guild with the given boost tier accepts
+pub fn upload_limit_mb(tier: PremiumTier) -> u16 {
+    match tier {
+        PremiumTier::Tier2 => 50,
+        PremiumTier::Tier3 => 100,
+        _ => DISCORD_MAX_FILE_SIZE_MB,
+    }
+}
+
+/// We only hold on to a snapshot of the config so a reload does not have to wait until the
+/// event is processed
+pub async fn read_context(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+) -> (Arc<Config>, Option<PremiumTier>) {
+    let data = ctx.data.read().await;
+    let config = data
+        .get::<Config>()
+        .expect("Expected Config struct in ContextData")
+        .clone();
+    let guild_tier =
+        guild_id.and_then(|guild_id| data.get::<GuildTiers>()?.get(&guild_id).copied());
+    (config, guild_tier)
+}
+
+/// A limit set for the channel wins over the one of the guild, DMs get the smallest limit
+pub fn max_upload_mb(
+    config: &Config,
+    channel_id: ChannelId,
+    guild_tier: Option<PremiumTier>,
+) -> u16 {
+    config
+        .channel_settings
+        .get(&channel_id.to_string())
+        .and_then(|settings| settings.max_upload_mb)
+        .or(guild_tier.map(upload_limit_mb))
+        .unwrap_or(DISCORD_MAX_FILE_SIZE_MB)
+}
+
+/// Whether the channel only wants the sound of videos
+pub fn audio_only(config: &Config, channel_id: ChannelId) -> bool {
+    config
+        .channel_settings
+        .get(&channel_id.to_string())
+        .is_some_and(|settings| settings.audio_only)
+}
 
-pub async fn send_debug_message(ctx: &Context, text: &str, channel_id: u64, user: &User) {
+pub async fn send_debug_message(http: &Http, text: &str, channel_id: u64, user: &User) {
     let response = MessageBuilder::new().push(text).mention(user).build();
-    let _ = ChannelId(channel_id).say(&ctx.http, &response).await;
+    let _ = ChannelId(channel_id).say(http, &response).await;
+}
+
+/// Why a message with files did not make it to Discord
+#[derive(Debug)]
+pub enum UploadError {
+    /// The webhook url is broken or the webhook was deleted, the bot can still post the files
+    InvalidWebhook(serenity::Error),
+    Failed(serenity::Error),
+    /// Discord took the message but did not send it back, so we can not tell if it was posted
+    Unconfirmed,
+}
+
+impl std::error::Error for UploadError {}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::InvalidWebhook(err) => write!(f, "The webhook is invalid: {err}"),
+            UploadError::Failed(err) => write!(f, "The upload failed: {err}"),
+            UploadError::Unconfirmed => write!(f, "Discord did not confirm the upload"),
+        }
+    }
 }
 
-async fn send_webhook_message(msg: &Message, webhook_url: &str, file_path: &PathBuf) {
-    let _payload_data = json!({
-        "name": msg.author,
-        "avatar":  msg.author.avatar_url().unwrap(),
-        "content": "youtube"
-    });
+/// The posted message, its attachments have the CDN urls
+pub type UploadResult = Result<Message, UploadError>;
 
+/// Reposts the files under the name and avatar of the author, `content` is only sent along
+/// when it is not empty
+async fn send_webhook_message(
+    task: &Task,
+    webhook: &Webhook,
+    file_paths: &[PathBuf],
+    content: &str,
+    mentions: MentionPolicy,
+) -> UploadResult {
     let http_webhook = Http::new("");
-    let webhook = Webhook::from_url(&http_webhook, webhook_url)
+    let mentioned_users = mentioned_users(task);
+
+    let message = webhook
+        .execute(&http_webhook, true, |w| {
+            if !content.is_empty() {
+                w.content(content);
+            }
+            w.username(&task.sender.name)
+                .avatar_url(task.sender.face())
+                .allowed_mentions(|m| allow_mentions(m, mentions, mentioned_users))
+                .add_files(file_paths)
+        })
         .await
-        .expect("Replace the webhook with your own");
-    webhook
-        .execute(&http_webhook, false, |w| {
-            w.username(&msg.author.name)
-                .avatar_url(&msg.author.avatar_url().unwrap())
-                .add_file(file_path)
+        .map_err(|err| match is_unknown_webhook(&err) {
+            true => UploadError::InvalidWebhook(err),
+            false => UploadError::Failed(err),
+        })?;
+    message.ok_or(UploadError::Unconfirmed)
+}
+
+/// Posts the files as the bot into the channel of the message, for when the webhook does not
+/// work. The author is named in front of the content since the message is not under their name.
+async fn send_bot_message(
+    task: &Task,
+    http: &Http,
+    file_paths: &[PathBuf],
+    content: &str,
+    mentions: MentionPolicy,
+) -> UploadResult {
+    let mentioned_users = mentioned_users(task);
+    let content = MessageBuilder::new()
+        .mention(&task.sender)
+        .push(" posted")
+        .push(if content.is_empty() { "" } else { ":\n" })
+        .push(content)
+        .build();
+
+    task.channel_id
+        .send_message(http, |m| {
+            m.content(content)
+                //The author is only named, the mention of them is not meant to ping
+                .allowed_mentions(|m| {
+                    allow_mentions(m, mentions, mentioned_users).replied_user(false)
+                })
+                .add_files(file_paths)
         })
         .await
-        .expect("Could not execute webhook.");
-}
-
-pub async fn delete_file(path: &PathBuf) {
-    //I really dont know why but for some reason it puts a space before the filename so we include it here in the delete command
-    info!("Removing {}", path.display());
-    match fs::remove_file(path) {
-        Ok(_) => {}
-        Err(err) => error!(
-            "Error deleting file after uploading it to discord : {} with error : {} ",
-            path.to_string_lossy(),
-            err
+        .map_err(UploadError::Failed)
+}
+
+/// Posts the files for the author with the webhook of the channel, `configured` is the webhook
+/// url from the config. A webhook of the bot that was deleted is created again, a configured one
+/// that is gone is replaced by one of the bot. When there is no working webhook the bot posts the
+/// files itself.
+pub async fn repost(
+    task: &Task,
+    http: &Http,
+    webhooks: &Webhooks,
+    configured: Option<&str>,
+    file_paths: &[PathBuf],
+    content: &str,
+    mentions: MentionPolicy,
+) -> UploadResult {
+    let send = || async {
+        let webhook = webhooks
+            .get(http, task.channel_id, configured)
+            .await
+            .map_err(UploadError::InvalidWebhook)?;
+        send_webhook_message(task, &webhook, file_paths, content, mentions).await
+    };
+
+    let mut result = send().await;
+    if matches!(result, Err(UploadError::InvalidWebhook(_)))
+        && webhooks.forget(task.channel_id, configured).await
+    {
+        info!(
+            "Webhook of channel {} is gone, creating a new one",
+            task.channel_id
+        );
+        result = send().await;
+    }
+    match result {
+        Err(UploadError::InvalidWebhook(err)) => {
+            error!(
+                "Webhook of channel {} does not work, posting as the bot: {err}",
+                task.channel_id
+            );
+            send_bot_message(task, http, file_paths, content, mentions).await
+        }
+        result => result,
+    }
+}
+
+fn mentioned_users(task: &Task) -> Vec<UserId> {
+    task.mentions
+        .iter()
+        .filter_map(|mention| match mention {
+            Mention::User(user_id) => Some(*user_id),
+            _ => None,
+        })
+        .collect()
+}
+
+fn allow_mentions(
+    m: &mut CreateAllowedMentions,
+    mentions: MentionPolicy,
+    users: Vec<UserId>,
+) -> &mut CreateAllowedMentions {
+    match mentions {
+        MentionPolicy::None => m.empty_parse(),
+        MentionPolicy::Users => m.empty_parse().users(users),
+        MentionPolicy::All => m
+            .parse(ParseValue::Users)
+            .parse(ParseValue::Roles)
+            .parse(ParseValue::Everyone),
+    }
+}
+
+/// Discord answers 404 for deleted webhooks and 401 when the token of the url is wrong
+fn is_unknown_webhook(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(err) => matches!(
+            err.status_code(),
+            Some(StatusCode::NOT_FOUND | StatusCode::UNAUTHORIZED)
         ),
+        _ => false,
+    }
+}
+
+/// Answers a deferred `/download` command with the files, the bot itself is the author so
+/// nobody gets pinged
+async fn send_followup_message(
+    command: &ApplicationCommandInteraction,
+    http: &Http,
+    file_paths: &[PathBuf],
+    content: &str,
+) -> UploadResult {
+    command
+        .create_followup_message(http, |f| {
+            if !content.is_empty() {
+                f.content(content);
+            }
+            f.allowed_mentions(|m| m.empty_parse())
+                .add_files(file_paths)
+        })
+        .await
+        .map_err(UploadError::Failed)
+}
+
+/// Tells only the user of a deferred `/download` command why no files are coming
+pub async fn send_ephemeral_followup(
+    command: &ApplicationCommandInteraction,
+    http: &Http,
+    text: &str,
+) {
+    let result = command
+        .create_followup_message(http, |f| f.content(text).ephemeral(true))
+        .await;
+    if let Err(err) = result {
+        error!("Could not answer command {}: {err}", command.id);
+    }
+}
+
+/// Splits the files into groups that can each be sent as one message, a message can have at most
+/// [DISCORD_MAX_ATTACHMENTS] files which together have to be smaller than `max_size` bytes.
+/// The order of the files is kept, files that are too large on their own are returned separately.
+pub fn batch_uploads(
+    files: Vec<(PathBuf, u64)>,
+    max_size: u64,
+) -> (Vec<Vec<PathBuf>>, Vec<(PathBuf, u64)>) {
+    let mut batches: Vec<Vec<PathBuf>> = Vec::new();
+    let mut too_large = Vec::new();
+    let mut batch_size = 0;
+
+    for (path, size) in files {
+        if size >= max_size {
+            too_large.push((path, size));
+            continue;
+        }
+
+        match batches.last_mut() {
+            Some(batch)
+                if batch.len() < DISCORD_MAX_ATTACHMENTS && batch_size + size < max_size =>
+            {
+                batch_size += size;
+                batch.push(path);
+            }
+            _ => {
+                batch_size = size;
+                batches.push(vec![path]);
+            }
+        }
+    }
+
+    (batches, too_large)
+}
+
+/// Under which key the upload of a media is cached, `None` when it must not be cached.
+/// A clip is only a part of the media, it must not end up under the key of the whole. The sound
+/// alone and the video in a lower quality are cached next to the video.
+pub fn cache_key(
+    platform: &str,
+    media_id: Option<String>,
+    options: &LoadOptions,
+) -> Option<String> {
+    let id = media_id.filter(|_| !options.is_clip())?;
+    let id = match (options.audio, options.max_resolution) {
+        (Some(format), _) => format!("{id}.{}", format.extension()),
+        (None, Some(resolution)) => format!("{id}@{resolution}p"),
+        (None, None) => id,
+    };
+    Some(cache::key(platform, &id))
+}
+
+/// Why a repost is incomplete, `None` when every file made it. Only then the original may be
+/// deleted, otherwise the files that failed to upload, could not be loaded or were too large to
+/// send would be lost.
+pub fn incomplete_repost(
+    upload_error: Option<UploadError>,
+    mut failed: Vec<LoadError>,
+    too_large: &[(PathBuf, u64)],
+    max_size: u64,
+) -> Option<LoadError> {
+    if let Some(err) = upload_error {
+        return Some(LoadError::Internal(Box::new(err)));
+    }
+    if !failed.is_empty() {
+        return Some(failed.remove(0));
+    }
+    let size = too_large.iter().map(|(_, size)| *size).max()?;
+    Some(LoadError::TooLarge {
+        size: Some(size),
+        limit: max_size,
+    })
+}
+
+/// The files a job downloaded, they are deleted when the job is done no matter how it ended.
+/// That includes jobs that were cancelled or ran into the timeout.
+#[derive(Default)]
+pub struct TempFiles {
+    paths: Vec<PathBuf>,
+}
+
+impl TempFiles {
+    pub fn track(&mut self, path: PathBuf) {
+        self.paths.push(path);
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            match fs::remove_file(path) {
+                Ok(_) => info!("Removed {}", path.display()),
+                //Renamed or already removed
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => error!("Could not remove {}: {err}", path.display()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use serenity::model::guild::PremiumTier;
+
+    use social_loaders::loaderror::LoadError;
+    use social_loaders::{AudioFormat, LoadOptions};
+
+    use crate::handlers::{
+        batch_uploads, cache_key, incomplete_repost, upload_limit_mb, TempFiles, UploadError,
+    };
+
+    fn files(sizes: &[u64]) -> Vec<(PathBuf, u64)> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| (PathBuf::from(i.to_string()), *size))
+            .collect()
+    }
+
+    #[test]
+    fn test_batches_respect_attachment_limit() {
+        let (batches, too_large) = batch_uploads(files(&[1; 23]), 100);
+
+        assert_eq!(
+            batches.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![10, 10, 3]
+        );
+        assert_eq!(batches[1][0], PathBuf::from("10"));
+        assert!(too_large.is_empty());
+    }
+
+    #[test]
+    fn test_batches_respect_size_limit() {
+        let (batches, too_large) = batch_uploads(files(&[40, 40, 40, 150, 10]), 100);
+
+        assert_eq!(
+            batches,
+            vec![
+                vec![PathBuf::from("0"), PathBuf::from("1")],
+                vec![PathBuf::from("2"), PathBuf::from("4")],
+            ]
+        );
+        assert_eq!(too_large, vec![(PathBuf::from("3"), 150)]);
+    }
+
+    #[test]
+    fn test_oversized_file_keeps_the_original() {
+        let (batches, too_large) = batch_uploads(files(&[40, 150]), 100);
+        assert_eq!(batches, vec![vec![PathBuf::from("0")]]);
+
+        let err = incomplete_repost(None, Vec::new(), &too_large, 100);
+        assert!(
+            matches!(
+                err,
+                Some(LoadError::TooLarge {
+                    size: Some(150),
+                    limit: 100
+                })
+            ),
+            "{err:?}"
+        );
+        let err = incomplete_repost(Some(UploadError::Unconfirmed), Vec::new(), &too_large, 100);
+        assert!(matches!(err, Some(LoadError::Internal(_))), "{err:?}");
+        assert!(incomplete_repost(None, Vec::new(), &[], 100).is_none());
+    }
+
+    #[test]
+    fn test_missing_file_of_a_post_keeps_the_original() {
+        let err = incomplete_repost(None, vec![LoadError::NotFound], &[], 100);
+        assert!(matches!(err, Some(LoadError::NotFound)), "{err:?}");
+    }
+
+    #[test]
+    fn test_upload_limit_follows_boost_tier() {
+        assert_eq!(upload_limit_mb(PremiumTier::Tier0), 8);
+        assert_eq!(upload_limit_mb(PremiumTier::Tier1), 8);
+        assert_eq!(upload_limit_mb(PremiumTier::Tier2), 50);
+        assert_eq!(upload_limit_mb(PremiumTier::Tier3), 100);
+        assert_eq!(upload_limit_mb(PremiumTier::Unknown), 8);
+    }
+
+    #[test]
+    fn test_temp_files_are_removed_on_drop() {
+        let dir = std::env::temp_dir().join("gamers_bot_temp_files");
+        std::fs::create_dir_all(&dir).unwrap();
+        let downloaded = dir.join("video.mp4");
+        std::fs::write(&downloaded, b"video").unwrap();
+
+        let mut temp_files = TempFiles::default();
+        temp_files.track(downloaded.clone());
+        //A file that was renamed in the meantime is skipped
+        temp_files.track(dir.join("renamed.mp4"));
+        drop(temp_files);
+
+        assert!(!downloaded.exists());
+    }
+
+    #[test]
+    fn test_cache_key_tells_qualities_apart() {
+        let id = || Some("abc".to_string());
+        let mut options = LoadOptions::new(8);
+        assert_eq!(cache_key("youtube", id(), &options).unwrap(), "youtube:abc");
+
+        options.max_resolution = Some(480);
+        assert_eq!(
+            cache_key("youtube", id(), &options).unwrap(),
+            "youtube:abc@480p"
+        );
+
+        options.audio = Some(AudioFormat::Mp3);
+        assert_eq!(
+            cache_key("youtube", id(), &options).unwrap(),
+            "youtube:abc.mp3"
+        );
+
+        options.start = Some(Duration::from_secs(1));
+        options.end = Some(Duration::from_secs(2));
+        assert_eq!(cache_key("youtube", id(), &options), None);
     }
 }
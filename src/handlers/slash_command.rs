@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use serenity::http::Http;
+use serenity::model::id::MessageId;
+use serenity::model::interactions::application_command::{
+    ApplicationCommand, ApplicationCommandInteraction, ApplicationCommandInteractionDataOption,
+    ApplicationCommandOptionType,
+};
+use serenity::model::interactions::InteractionResponseType;
+use serenity::prelude::Context;
+use social_loaders::{parse_timestamp, LoadOptions};
+use tracing::{error, info};
+use url::Url;
+
+use crate::handlers::task::{parse_url, Task};
+use crate::handlers::{audio_only, max_upload_mb, read_context};
+use crate::jobs::{Delivery, Job, JobQueue};
+use format as f;
+
+pub const NAME: &str = "download";
+
+/// Resolutions offered for the `quality` option
+const QUALITIES: [u32; 4] = [360, 480, 720, 1080];
+
+/// What the user asked for with `/download`
+#[derive(Debug, PartialEq)]
+struct Request {
+    url: Url,
+    /// `None` when the user left it to the channel
+    audio_only: Option<bool>,
+    quality: Option<u32>,
+    spoiler: bool,
+    start: Option<Duration>,
+    end: Option<Duration>,
+}
+
+/// Registers `/download` for every guild and DM. Discord keeps global commands, registering it
+/// again on every start only updates the options.
+pub async fn register(http: &Http) -> serenity::Result<ApplicationCommand> {
+    ApplicationCommand::create_global_application_command(http, |command| {
+        command
+            .name(NAME)
+            .description("Download a video or image and post it here")
+            .create_option(|option| {
+                option
+                    .name("url")
+                    .description("Link to the post or video")
+                    .kind(ApplicationCommandOptionType::String)
+                    .required(true)
+            })
+            .create_option(|option| {
+                option
+                    .name("audio_only")
+                    .description("Only post the sound of the video")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|option| {
+                option
+                    .name("quality")
+                    .description("Highest resolution of the video")
+                    .kind(ApplicationCommandOptionType::Integer);
+                for quality in QUALITIES {
+                    option.add_int_choice(f!("{quality}p"), quality as i32);
+                }
+                option
+            })
+            .create_option(|option| {
+                option
+                    .name("spoiler")
+                    .description("Post the files as spoiler")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|option| {
+                option
+                    .name("start")
+                    .description("Only download from this point on, like 1:23")
+                    .kind(ApplicationCommandOptionType::String)
+            })
+            .create_option(|option| {
+                option
+                    .name("end")
+                    .description("Only download until this point, like 1:45")
+                    .kind(ApplicationCommandOptionType::String)
+            })
+    })
+    .await
+}
+
+/// Runs `/download` through the same queue as the links of messages. Downloads take longer than
+/// the 3 seconds Discord waits for an answer, so we defer it and post the files as a follow up.
+pub async fn handle(ctx: &Context, command: ApplicationCommandInteraction, queue: &JobQueue) {
+    if command.data.name != NAME {
+        return;
+    }
+
+    let request = match parse(&command.data.options) {
+        Ok(request) => request,
+        Err(message) => {
+            let _ = command
+                .create_interaction_response(&ctx.http, |response| {
+                    response
+                        .kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|data| data.content(message).ephemeral(true))
+                })
+                .await;
+            return;
+        }
+    };
+    info!(
+        "{} used /{NAME} for {} in channel {}",
+        command.user, request.url, command.channel_id
+    );
+    if let Err(err) = command.defer(&ctx.http).await {
+        error!("Could not defer command {}: {err}", command.id);
+        return;
+    }
+
+    let (config, guild_tier) = read_context(ctx, command.guild_id).await;
+    let mut options = LoadOptions::new(max_upload_mb(&config, command.channel_id, guild_tier));
+    options.max_resolution = request.quality;
+    options.audio = request
+        .audio_only
+        .unwrap_or_else(|| audio_only(&config, command.channel_id))
+        .then_some(config.audio_format);
+    options.start = request.start;
+    options.end = request.end;
+    options.compress = config.compression.enabled;
+
+    let job = Job {
+        message_id: MessageId(command.id.0),
+        task: Task::from_url(
+            command.user.clone(),
+            command.channel_id,
+            command.guild_id,
+            request.url,
+        ),
+        config,
+        options,
+        spoiler: request.spoiler,
+        delivery: Delivery::Interaction(Box::new(command)),
+        http: ctx.http.clone(),
+    };
+    //The queue answers the command when it does not take the job
+    if let Err(err) = queue.enqueue(job) {
+        info!("Command is not downloaded: {err:?}");
+    }
+}
+
+/// Checks the options of the command, the error is the message for the user
+fn parse(options: &[ApplicationCommandInteractionDataOption]) -> Result<Request, String> {
+    let value = |name: &str| {
+        options
+            .iter()
+            .find(|option| option.name == name)
+            .and_then(|option| option.value.as_ref())
+    };
+    let flag = |name: &str| value(name).and_then(|value| value.as_bool());
+    let timestamp = |name: &str| match value(name).and_then(|value| value.as_str()) {
+        Some(text) => parse_timestamp(text)
+            .map(Some)
+            .ok_or_else(|| f!("{text} is not a time, use something like 1:23")),
+        None => Ok(None),
+    };
+
+    let url = value("url")
+        .and_then(|value| value.as_str())
+        .and_then(|url| parse_url(url.trim()))
+        .ok_or("That is not a link i can download")?;
+    let quality = value("quality")
+        .and_then(|value| value.as_u64())
+        .map(|quality| quality as u32);
+    let start = timestamp("start")?;
+    let end = timestamp("end")?;
+    if let (Some(start), Some(end)) = (start, end) {
+        if start >= end {
+            return Err("The start has to be before the end".into());
+        }
+    }
+
+    Ok(Request {
+        url,
+        audio_only: flag("audio_only"),
+        quality,
+        spoiler: flag("spoiler") == Some(true),
+        start,
+        end,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use serenity::model::interactions::application_command::ApplicationCommandInteractionDataOption;
+    use url::Url;
+
+    use crate::handlers::slash_command::{parse, Request};
+
+    fn options(value: serde_json::Value) -> Vec<ApplicationCommandInteractionDataOption> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let request = parse(&options(json!([
+            {"name": "url", "type": 3, "value": "https://youtu.be/UT5F9AXjwhg"},
+            {"name": "quality", "type": 4, "value": 720},
+            {"name": "spoiler", "type": 5, "value": true},
+            {"name": "audio_only", "type": 5, "value": false},
+            {"name": "start", "type": 3, "value": "1:23"},
+        ])));
+
+        assert_eq!(
+            request,
+            Ok(Request {
+                url: Url::parse("https://youtu.be/UT5F9AXjwhg").unwrap(),
+                audio_only: Some(false),
+                quality: Some(720),
+                spoiler: true,
+                start: Some(Duration::from_secs(83)),
+                end: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_reject_invalid_options() {
+        let url = json!({"name": "url", "type": 3, "value": "https://youtu.be/UT5F9AXjwhg"});
+
+        assert!(parse(&options(
+            json!([{"name": "url", "type": 3, "value": "youtube"}])
+        ))
+        .is_err());
+        assert!(parse(&options(
+            json!([url, {"name": "end", "type": 3, "value": "soon"}])
+        ))
+        .is_err());
+        assert!(parse(&options(json!([
+            url,
+            {"name": "start", "type": 3, "value": "1:45"},
+            {"name": "end", "type": 3, "value": "1:23"},
+        ])))
+        .is_err());
+    }
+}
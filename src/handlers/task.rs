@@ -0,0 +1,390 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity::model::prelude::{Mention, User};
+use social_loaders::cache;
+use social_loaders::{parse_timestamp, Registry};
+use url::Url;
+
+/// Longest content discord accepts for a single message
+const DISCORD_MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// Everything we need from a message to download its links and repost it
+pub struct Task {
+    pub sender: User,
+    pub channel_id: ChannelId,
+    /// `None` for direct messages
+    pub guild_id: Option<GuildId>,
+    pub mentions: Vec<Mention>,
+    /// Every distinct link of the message a downloader can handle in the order they appear, at
+    /// most the configured number per message
+    pub urls: Vec<Url>,
+    /// The parts of videos the user asked for instead of the whole video
+    pub clips: HashMap<Url, Clip>,
+    /// The message as the user wrote it, links included
+    pub content: String,
+    /// Set when the message was a reply, webhooks can not reply so we link to it instead
+    pub reply: Option<Reply>,
+}
+
+/// Part of a video the user asked for by writing `<url> 1:23-1:45`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Clip {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+pub struct Reply {
+    pub author: String,
+    /// Jump link to the message that was replied to
+    pub link: String,
+}
+
+impl Task {
+    pub fn from_message(msg: &Message, max_urls: usize, registry: &Registry) -> Task {
+        let embed_urls = msg.embeds.iter().filter_map(|embed| embed.url.as_deref());
+        let urls = extract_urls(&msg.content, embed_urls, max_urls, |url| {
+            media_key(registry, url)
+        });
+
+        Task {
+            sender: msg.author.clone(),
+            channel_id: msg.channel_id,
+            guild_id: msg.guild_id,
+            mentions: msg.mentions.iter().map(|user| user.id.into()).collect(),
+            urls,
+            clips: extract_clips(&msg.content),
+            content: msg.content.clone(),
+            reply: reply(msg),
+        }
+    }
+
+    /// A task for a single link the user gave us directly, there is no text to repost
+    pub fn from_url(
+        sender: User,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+        url: Url,
+    ) -> Task {
+        Task {
+            sender,
+            channel_id,
+            guild_id,
+            mentions: Vec::new(),
+            urls: vec![url],
+            clips: HashMap::new(),
+            content: String::new(),
+            reply: None,
+        }
+    }
+
+    /// What the user wrote around the links we reposted, links we could not handle stay in
+    pub fn text_without(&self, handled: &[Url]) -> String {
+        strip_urls(&self.content, handled)
+    }
+
+    /// Content of the repost: the reply as a quote followed by the text without the links and
+    /// then `links`, which are never cut off when the text is too long
+    pub fn repost_content(&self, handled: &[Url], links: &[String]) -> String {
+        let mut content = String::new();
+        if let Some(reply) = &self.reply {
+            content.push_str(&format!("> Replying to {}: {}\n", reply.author, reply.link));
+        }
+        content.push_str(&self.text_without(handled));
+
+        let links = links.join("\n");
+        let room = match links.is_empty() {
+            true => DISCORD_MAX_MESSAGE_LENGTH,
+            false => DISCORD_MAX_MESSAGE_LENGTH.saturating_sub(links.chars().count() + 1),
+        };
+        let text = content.trim_end().chars().take(room).collect::<String>();
+        match (text.is_empty(), links.is_empty()) {
+            (_, true) => text,
+            (true, false) => links,
+            (false, false) => text + "\n" + &links,
+        }
+    }
+}
+
+fn reply(msg: &Message) -> Option<Reply> {
+    if let Some(referenced) = &msg.referenced_message {
+        return Some(Reply {
+            author: referenced.author.name.clone(),
+            link: referenced.link(),
+        });
+    }
+
+    //Discord leaves out the referenced message when it was deleted or could not be loaded
+    let reference = msg.message_reference.as_ref()?;
+    Some(Reply {
+        author: "a message".into(),
+        link: reference
+            .message_id?
+            .link(reference.channel_id, reference.guild_id),
+    })
+}
+
+/// Collects the links from the content, or the ones discord found in the embeds when the content
+/// has none. Links with the same `media_key` are only kept once, links without one are not
+/// supported and are removed. Only the first `max_urls` are kept, other links in the message do
+/// not take away from the cap.
+pub fn extract_urls<'a>(
+    content: &str,
+    embed_urls: impl Iterator<Item = &'a str>,
+    max_urls: usize,
+    media_key: impl Fn(&Url) -> Option<String>,
+) -> Vec<Url> {
+    let keyed = |url: Url| Some((media_key(&url)?, url));
+    let in_content = content
+        .split_whitespace()
+        .filter_map(parse_word)
+        .filter_map(keyed)
+        .collect::<Vec<(String, Url)>>();
+    //The embeds repeat the links of the content, often as another url of the same media like the
+    //`youtube.com/watch?v=` of a `youtu.be` link
+    let candidates = match in_content.is_empty() {
+        true => embed_urls
+            .filter_map(|url| parse_url(url.trim()))
+            .filter_map(keyed)
+            .collect(),
+        false => in_content,
+    };
+
+    let mut seen = HashSet::new();
+    candidates
+        .into_iter()
+        .filter(|(key, _)| seen.insert(key.clone()))
+        .map(|(_, url)| url)
+        .take(max_urls)
+        .collect()
+}
+
+/// Two links with the same key lead to the same media, `None` when no downloader supports the
+/// link. Links a downloader finds no media id in are only the same when they are equal.
+pub fn media_key(registry: &Registry, url: &Url) -> Option<String> {
+    let downloader = registry.find(url)?;
+    Some(match downloader.media_id(url) {
+        Some(media_id) => cache::key(downloader.name(), &media_id),
+        None => url.to_string(),
+    })
+}
+
+/// Finds the `start-end` ranges written right after a link
+pub fn extract_clips(content: &str) -> HashMap<Url, Clip> {
+    let words = content.split_whitespace().collect::<Vec<&str>>();
+    words
+        .windows(2)
+        .filter_map(|pair| Some((parse_word(pair[0])?, parse_clip(pair[1])?)))
+        .collect()
+}
+
+fn parse_clip(word: &str) -> Option<Clip> {
+    let (start, end) = word.split_once('-')?;
+    let clip = Clip {
+        start: parse_timestamp(start)?,
+        end: parse_timestamp(end)?,
+    };
+    (clip.start < clip.end).then_some(clip)
+}
+
+/// Removes the links from the text together with the clip ranges written after them, the lines
+/// the user wrote are kept as they are
+pub fn strip_urls(content: &str, urls: &[Url]) -> String {
+    content
+        .lines()
+        .map(|line| {
+            let mut words = Vec::new();
+            let mut after_url = false;
+            for word in line.split_whitespace() {
+                let is_url = parse_word(word).is_some_and(|url| urls.contains(&url));
+                let is_clip = after_url && parse_clip(word).is_some();
+                after_url = is_url;
+                if !is_url && !is_clip {
+                    words.push(word);
+                }
+            }
+            words.join(" ")
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// A word can be wrapped in `<>` to suppress the embed, in brackets or be followed by punctuation
+fn parse_word(word: &str) -> Option<Url> {
+    let start = word.find("http")?;
+    let url =
+        word[start..].trim_end_matches(['>', ')', ']', '.', ',', ';', ':', '!', '?', '"', '\'']);
+    parse_url(url)
+}
+
+pub(crate) fn parse_url(url: &str) -> Option<Url> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https") && url.host().is_some())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use social_loaders::Registry;
+    use url::Url;
+
+    use crate::handlers::task::{
+        extract_clips, extract_urls, media_key, strip_urls, Clip, Reply, Task,
+    };
+
+    fn urls(content: &str, embeds: &[&str], max_urls: usize) -> Vec<String> {
+        extract_urls(content, embeds.iter().copied(), max_urls, |url| {
+            Some(url.to_string())
+        })
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
+
+    #[test]
+    fn test_extract_every_url_once() {
+        let content = "look at this https://redd.it/abc and <https://youtu.be/xyz>, \
+                       also (https://redd.it/abc) ftp://example.com/file";
+
+        assert_eq!(
+            urls(content, &[], 10),
+            vec!["https://redd.it/abc", "https://youtu.be/xyz"]
+        );
+    }
+
+    #[test]
+    fn test_embed_of_the_same_media_is_not_loaded_twice() {
+        let registry = Registry::with_builtin();
+        let extract = |content: &str, embeds: &[&str]| {
+            extract_urls(content, embeds.iter().copied(), 5, |url| {
+                media_key(&registry, url)
+            })
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<String>>()
+        };
+
+        let watch = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+        assert_eq!(
+            extract("https://youtu.be/dQw4w9WgXcQ", &[watch]),
+            vec!["https://youtu.be/dQw4w9WgXcQ"]
+        );
+        assert_eq!(
+            extract(&format!("https://youtu.be/dQw4w9WgXcQ {watch}"), &[]),
+            vec!["https://youtu.be/dQw4w9WgXcQ"]
+        );
+        //Without a link in the content the embeds are all we have
+        assert_eq!(extract("look at this", &[watch]), vec![watch]);
+    }
+
+    #[test]
+    fn test_extract_respects_cap() {
+        let content = "https://a.com/1 https://a.com/2 https://a.com/3";
+        assert_eq!(
+            urls(content, &[], 2),
+            vec!["https://a.com/1", "https://a.com/2"]
+        );
+        assert!(urls(content, &[], 0).is_empty());
+        assert!(urls("no links here", &[], 5).is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_urls_do_not_count_towards_cap() {
+        let registry = Registry::with_builtin();
+        let content = "https://a.com/1 https://a.com/2 https://a.com/3 https://a.com/4 \
+                       https://a.com/5 https://redd.it/abc";
+
+        let urls = extract_urls(content, std::iter::empty(), 5, |url| {
+            media_key(&registry, url)
+        });
+        assert_eq!(urls, vec![Url::parse("https://redd.it/abc").unwrap()]);
+    }
+
+    #[test]
+    fn test_extract_clips_after_url() {
+        let content = "best part https://youtu.be/abc 1:23-1:45 and https://youtu.be/xyz 2:00 \
+                       https://redd.it/def 0:30-0:10";
+        let clips = extract_clips(content);
+
+        assert_eq!(clips.len(), 1);
+        assert_eq!(
+            clips.get(&Url::parse("https://youtu.be/abc").unwrap()),
+            Some(&Clip {
+                start: Duration::from_secs(83),
+                end: Duration::from_secs(105),
+            })
+        );
+    }
+
+    #[test]
+    fn test_strip_urls_keeps_text() {
+        let handled = vec![Url::parse("https://redd.it/abc").unwrap()];
+        let content = "haha <@123> https://redd.it/abc\nsecond line https://other.com/x";
+
+        assert_eq!(
+            strip_urls(content, &handled),
+            "haha <@123>\nsecond line https://other.com/x"
+        );
+        assert_eq!(strip_urls("https://redd.it/abc", &handled), "");
+        assert_eq!(
+            strip_urls("the goal https://redd.it/abc 1:23-1:45 lol", &handled),
+            "the goal lol"
+        );
+        //Without the link in front it is just text
+        assert_eq!(strip_urls("from 1:23-1:45", &handled), "from 1:23-1:45");
+    }
+
+    #[test]
+    fn test_repost_content_quotes_reply() {
+        let handled = vec![Url::parse("https://redd.it/abc").unwrap()];
+        let task = |content: &str, reply: Option<Reply>| Task {
+            sender: Default::default(),
+            channel_id: Default::default(),
+            guild_id: None,
+            mentions: Vec::new(),
+            urls: handled.clone(),
+            clips: HashMap::new(),
+            content: content.into(),
+            reply,
+        };
+
+        assert_eq!(
+            task("https://redd.it/abc", None).repost_content(&handled, &[]),
+            ""
+        );
+        assert_eq!(
+            task("so true <@42> https://redd.it/abc", None).repost_content(&handled, &[]),
+            "so true <@42>"
+        );
+        let reply = Reply {
+            author: "someone".into(),
+            link: "https://discord.com/channels/1/2/3".into(),
+        };
+        assert_eq!(
+            task("https://redd.it/abc", Some(reply)).repost_content(&handled, &[]),
+            "> Replying to someone: https://discord.com/channels/1/2/3"
+        );
+        assert_eq!(
+            task(&"a".repeat(3000), None)
+                .repost_content(&handled, &[])
+                .len(),
+            2000
+        );
+
+        let links = vec!["https://cdn.discordapp.com/attachments/1/2/a.mp4".to_string()];
+        let content = task(&"a".repeat(3000), None).repost_content(&handled, &links);
+        assert_eq!(content.len(), 2000);
+        assert!(content.ends_with("\nhttps://cdn.discordapp.com/attachments/1/2/a.mp4"));
+        assert_eq!(
+            task("https://redd.it/abc", None).repost_content(&handled, &links),
+            links[0]
+        );
+    }
+}
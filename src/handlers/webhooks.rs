@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use serenity::http::{Http, HttpError};
+use serenity::model::id::ChannelId;
+use serenity::model::webhook::Webhook;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::handlers::is_unknown_webhook;
+
+/// Name of the webhooks the bot creates, the reposts carry the name of the author anyway
+pub const WEBHOOK_NAME: &str = "Gamers Bot";
+
+/// The webhooks the bot reposts with, one per channel. They are looked up or created the first
+/// time a channel needs one and kept, so a message costs no extra requests.
+#[derive(Default)]
+pub struct Webhooks {
+    by_channel: Mutex<HashMap<ChannelId, Webhook>>,
+    /// The webhooks of the urls in the config, `None` for urls whose webhook is gone
+    configured: Mutex<HashMap<String, Option<Webhook>>>,
+}
+
+impl Webhooks {
+    /// The webhook for the channel. A url from the config wins as long as its webhook exists,
+    /// otherwise the bot uses the webhook it created earlier or creates one, which needs the
+    /// `MANAGE_WEBHOOKS` permission.
+    pub async fn get(
+        &self,
+        http: &Http,
+        channel_id: ChannelId,
+        configured: Option<&str>,
+    ) -> serenity::Result<Webhook> {
+        if let Some(url) = configured {
+            if let Some(webhook) = self.configured(http, channel_id, url).await? {
+                return Ok(webhook);
+            }
+        }
+
+        let mut by_channel = self.by_channel.lock().await;
+        if let Some(webhook) = by_channel.get(&channel_id) {
+            return Ok(webhook.clone());
+        }
+        //After a restart the webhook the bot created last time is still there
+        let bot_id = http.get_current_user().await?.id;
+        let existing = channel_id
+            .webhooks(http)
+            .await?
+            .into_iter()
+            .find(|webhook| {
+                webhook.user.as_ref().map(|user| user.id) == Some(bot_id) && webhook.token.is_some()
+            });
+        let webhook = match existing {
+            Some(webhook) => webhook,
+            None => {
+                info!("Creating a webhook in channel {channel_id}");
+                channel_id.create_webhook(http, WEBHOOK_NAME).await?
+            }
+        };
+        by_channel.insert(channel_id, webhook.clone());
+        Ok(webhook)
+    }
+
+    /// The webhook of a url from the config, `None` once it turned out to be gone. Other errors
+    /// are not kept, the next message tries again.
+    async fn configured(
+        &self,
+        http: &Http,
+        channel_id: ChannelId,
+        url: &str,
+    ) -> serenity::Result<Option<Webhook>> {
+        let mut configured = self.configured.lock().await;
+        if let Some(webhook) = configured.get(url) {
+            return Ok(webhook.clone());
+        }
+        let webhook = match http.get_webhook_from_url(url).await {
+            Ok(webhook) => Some(webhook),
+            Err(err) if is_unknown_webhook(&err) || is_invalid_url(&err) => {
+                warn!("The webhook configured for channel {channel_id} does not work: {err}");
+                None
+            }
+            Err(err) => return Err(err),
+        };
+        configured.insert(url.to_string(), webhook.clone());
+        Ok(webhook)
+    }
+
+    /// Drops the webhook of the channel after it stopped working, for example because somebody
+    /// deleted it. A configured webhook is not used anymore, the next [get](Webhooks::get) uses
+    /// one of the bot instead. A webhook of the bot is created again. Returns whether there was
+    /// one.
+    pub async fn forget(&self, channel_id: ChannelId, configured: Option<&str>) -> bool {
+        if let Some(url) = configured {
+            let mut configured = self.configured.lock().await;
+            if let Some(webhook @ Some(_)) = configured.get_mut(url) {
+                *webhook = None;
+                return true;
+            }
+        }
+        self.by_channel.lock().await.remove(&channel_id).is_some()
+    }
+}
+
+/// A url in the config that is no webhook url at all
+fn is_invalid_url(err: &serenity::Error) -> bool {
+    matches!(err, serenity::Error::Http(err) if matches!(**err, HttpError::InvalidWebhook | HttpError::Url(_)))
+}
+
+#[cfg(test)]
+mod test {
+    use serenity::model::id::{ChannelId, WebhookId};
+
+    use crate::handlers::webhooks::Webhooks;
+    use crate::test_support::Discord;
+
+    const BOT: &str = r#"{"id": "9", "username": "bot", "discriminator": "0001", "avatar": null, "bot": true,
+            "mfa_enabled": false}"#;
+
+    fn webhook(id: u64) -> String {
+        format!(
+            r#"{{"id": "{id}", "type": 1, "channel_id": "5", "name": "hook", "avatar": null,
+                "token": "secret", "user": {BOT}}}"#
+        )
+    }
+
+    /// A channel without webhooks, the bot can create one with the id 7
+    fn discord() -> Discord {
+        let discord = Discord::start();
+        discord.answer("GET /api/v10/users/@me", BOT);
+        discord.answer("GET /api/v10/channels/5/webhooks", "[]");
+        discord.answer("POST /api/v10/channels/5/webhooks", &webhook(7));
+        discord
+    }
+
+    /// Discord only takes urls with ids and tokens of the real length
+    fn configured_url(token: &str) -> String {
+        format!("https://discord.com/api/webhooks/12345678901234567/{token:a<64}")
+    }
+
+    fn requests(requests: Vec<(String, String)>) -> Vec<String> {
+        requests.into_iter().map(|(request, _)| request).collect()
+    }
+
+    #[tokio::test]
+    async fn test_configured_webhook_is_only_fetched_once() {
+        let discord = discord();
+        let url = configured_url("secret");
+        let path = url.replace("https://discord.com/api", "/api/v10");
+        discord.answer(&format!("GET {path}"), &webhook(12345678901234567));
+        let webhooks = Webhooks::default();
+        let url = Some(url.as_str());
+
+        for _ in 0..2 {
+            let webhook = webhooks.get(&discord.http, ChannelId(5), url).await;
+            assert_eq!(webhook.unwrap().id, WebhookId(12345678901234567));
+        }
+        assert_eq!(
+            requests(discord.requests(1).await),
+            vec![format!("GET {path}")]
+        );
+
+        //It stopped working while we kept it
+        assert!(webhooks.forget(ChannelId(5), url).await);
+        let webhook = webhooks.get(&discord.http, ChannelId(5), url).await;
+        assert_eq!(webhook.unwrap().id, WebhookId(7));
+    }
+
+    #[tokio::test]
+    async fn test_deleted_configured_webhook_is_replaced_by_one_of_the_bot() {
+        let discord = discord();
+        let webhooks = Webhooks::default();
+        let url = configured_url("deleted");
+        let path = url.replace("https://discord.com/api", "/api/v10");
+        let url = Some(url.as_str());
+
+        for _ in 0..2 {
+            let webhook = webhooks.get(&discord.http, ChannelId(5), url).await;
+            assert_eq!(webhook.unwrap().id, WebhookId(7));
+        }
+        assert_eq!(
+            requests(discord.requests(4).await),
+            vec![
+                format!("GET {path}"),
+                "GET /api/v10/users/@me".to_string(),
+                "GET /api/v10/channels/5/webhooks".to_string(),
+                "POST /api/v10/channels/5/webhooks".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serenity::http::Http;
+use serenity::model::id::{MessageId, UserId};
+use serenity::model::interactions::application_command::ApplicationCommandInteraction;
+use social_loaders::LoadOptions;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::handlers::send_ephemeral_followup;
+use crate::handlers::task::Task;
+
+/// Everything a worker needs to download the links of a message and repost them
+pub struct Job {
+    /// The message the links came from, it also identifies the job. Jobs of a `/download`
+    /// command use the id of the interaction.
+    pub message_id: MessageId,
+    pub task: Task,
+    /// The config at the time the message was sent
+    pub config: Arc<Config>,
+    pub options: LoadOptions,
+    /// Upload the files as spoilers
+    pub spoiler: bool,
+    pub delivery: Delivery,
+    pub http: Arc<Http>,
+}
+
+/// Where the result of a job is posted
+pub enum Delivery {
+    /// Reposted through the webhook of the channel, the original message is deleted
+    Webhook,
+    /// Follow up messages to a deferred `/download` command
+    Interaction(Box<ApplicationCommandInteraction>),
+}
+
+/// Answers a deferred `/download` command whose job ends without a result, Discord would show
+/// the command as thinking until it gives up otherwise. Jobs of the webhook keep their original
+/// message, nobody waits for an answer.
+struct Unanswered {
+    command: Option<(Arc<Http>, Box<ApplicationCommandInteraction>)>,
+    reason: String,
+}
+
+impl Unanswered {
+    fn new(job: &Job, reason: &str) -> Unanswered {
+        let command = match &job.delivery {
+            Delivery::Interaction(command) => Some((job.http.clone(), command.clone())),
+            Delivery::Webhook => None,
+        };
+        Unanswered {
+            command,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The job came to an end, it answered the command itself
+    fn answered(mut self) {
+        self.command = None;
+    }
+}
+
+impl Drop for Unanswered {
+    fn drop(&mut self) {
+        let Some((http, command)) = self.command.take() else {
+            return;
+        };
+        let reason = std::mem::take(&mut self.reason);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn(async move {
+                send_ephemeral_followup(&command, &http, &reason).await;
+            })),
+            Err(_) => warn!("Command {} is not answered: {reason}", command.id),
+        }
+    }
+}
+
+const SHUTTING_DOWN: &str = "The bot is restarting, try again in a minute";
+const CANCELLED: &str = "The download was stopped before it was done";
+
+type Runner = Arc<dyn Fn(Job) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+#[derive(Debug, PartialEq)]
+pub enum EnqueueError {
+    /// The bot is shutting down and does not take new jobs
+    ShuttingDown,
+}
+
+/// Runs jobs in the background with at most `max_concurrent` at the same time. Waiting jobs are
+/// taken from the users in turns, so one user posting a lot of links does not block everyone
+/// else. Cheap to clone, every clone refers to the same queue.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    /// Signalled when a job was added or the queue stops accepting jobs
+    queued: Notify,
+    /// Signalled when the last job finished
+    drained: Notify,
+    slots: Arc<Semaphore>,
+    timeout: Duration,
+    runner: Runner,
+}
+
+struct State {
+    /// Waiting jobs per user, the user in front is the next one to get a job started
+    waiting: VecDeque<(UserId, VecDeque<Job>)>,
+    running: HashMap<MessageId, AbortHandle>,
+    accepting: bool,
+}
+
+impl State {
+    fn next(&mut self) -> Option<Job> {
+        let (user, mut jobs) = self.waiting.pop_front()?;
+        let job = jobs.pop_front();
+        if !jobs.is_empty() {
+            self.waiting.push_back((user, jobs));
+        }
+        job
+    }
+
+    fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty()
+    }
+}
+
+impl JobQueue {
+    /// Starts the dispatcher, `runner` is called for every job. A job that runs longer than
+    /// `timeout` is dropped, which also kills the yt-dlp and ffmpeg processes it started.
+    pub fn new<F, Fut>(max_concurrent: usize, timeout: Duration, runner: F) -> JobQueue
+    where
+        F: Fn(Job) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let inner = Arc::new(Inner {
+            state: Mutex::new(State {
+                waiting: VecDeque::new(),
+                running: HashMap::new(),
+                accepting: true,
+            }),
+            queued: Notify::new(),
+            drained: Notify::new(),
+            slots: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            timeout,
+            runner: Arc::new(move |job| Box::pin(runner(job))),
+        });
+
+        tokio::spawn(dispatch(inner.clone()));
+        JobQueue { inner }
+    }
+
+    pub fn enqueue(&self, job: Job) -> Result<(), EnqueueError> {
+        let mut state = self.inner.state.lock().unwrap();
+        if !state.accepting {
+            drop(Unanswered::new(&job, SHUTTING_DOWN));
+            return Err(EnqueueError::ShuttingDown);
+        }
+
+        let user = job.task.sender.id;
+        match state.waiting.iter_mut().find(|(id, _)| *id == user) {
+            Some((_, jobs)) => jobs.push_back(job),
+            None => state.waiting.push_back((user, VecDeque::from([job]))),
+        }
+        drop(state);
+
+        self.inner.queued.notify_one();
+        Ok(())
+    }
+
+    /// Removes the job if it is still waiting or aborts it when it is already running.
+    /// Returns false when there is no job for the message.
+    pub fn cancel(&self, message_id: MessageId) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+
+        let cancelled = if let Some(handle) = state.running.remove(&message_id) {
+            handle.abort();
+            true
+        } else {
+            let mut found = false;
+            for (_, jobs) in state.waiting.iter_mut() {
+                let before = jobs.len();
+                jobs.retain(|job| {
+                    let cancelled = job.message_id == message_id;
+                    if cancelled {
+                        drop(Unanswered::new(job, CANCELLED));
+                    }
+                    !cancelled
+                });
+                found |= jobs.len() != before;
+            }
+            state.waiting.retain(|(_, jobs)| !jobs.is_empty());
+            found
+        };
+
+        if state.is_idle() {
+            self.inner.drained.notify_waiters();
+        }
+        cancelled
+    }
+
+    /// Stops taking new jobs and waits until the waiting and running jobs are done. Jobs that
+    /// are not done within `grace` are aborted, returns whether everything finished in time.
+    pub async fn shutdown(&self, grace: Duration) -> bool {
+        self.inner.state.lock().unwrap().accepting = false;
+        self.inner.queued.notify_one();
+
+        let deadline = Instant::now() + grace;
+        loop {
+            let drained = self.inner.drained.notified();
+            if self.inner.state.lock().unwrap().is_idle() {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, drained).await.is_err() {
+                let mut state = self.inner.state.lock().unwrap();
+                warn!(
+                    "Aborting {} running and {} waiting jobs on shutdown",
+                    state.running.len(),
+                    state
+                        .waiting
+                        .iter()
+                        .map(|(_, jobs)| jobs.len())
+                        .sum::<usize>()
+                );
+                for (_, jobs) in state.waiting.drain(..) {
+                    for job in jobs {
+                        drop(Unanswered::new(&job, SHUTTING_DOWN));
+                    }
+                }
+                for (_, handle) in state.running.drain() {
+                    handle.abort();
+                }
+                return false;
+            }
+        }
+    }
+}
+
+async fn dispatch(inner: Arc<Inner>) {
+    loop {
+        let Ok(slot) = inner.slots.clone().acquire_owned().await else {
+            return;
+        };
+
+        let mut slot = Some(slot);
+        loop {
+            match start_next(&inner, &mut slot) {
+                Next::Started => break,
+                Next::Empty => inner.queued.notified().await,
+                Next::Closed => return,
+            }
+        }
+    }
+}
+
+enum Next {
+    Started,
+    Empty,
+    /// The queue is empty and does not accept new jobs anymore
+    Closed,
+}
+
+/// Takes the next waiting job and spawns it. This happens under one lock so the job is never
+/// missing from both the waiting queue and the running map, a shutdown would think we are done.
+fn start_next(inner: &Arc<Inner>, slot: &mut Option<OwnedSemaphorePermit>) -> Next {
+    let mut state = inner.state.lock().unwrap();
+    match state.next() {
+        Some(job) => {
+            let message_id = job.message_id;
+            let slot = slot.take().expect("Every job gets a slot");
+            let handle = tokio::spawn(run(inner.clone(), job, slot));
+            state.running.insert(message_id, handle.abort_handle());
+            Next::Started
+        }
+        None if state.accepting => Next::Empty,
+        None => Next::Closed,
+    }
+}
+
+/// Aborting the job drops this future, the [Unanswered] of it then tells the user
+async fn run(inner: Arc<Inner>, job: Job, _slot: OwnedSemaphorePermit) {
+    let message_id = job.message_id;
+    let mut unanswered = Unanswered::new(&job, CANCELLED);
+    match tokio::time::timeout(inner.timeout, (inner.runner)(job)).await {
+        Ok(()) => unanswered.answered(),
+        Err(_) => {
+            warn!(
+                "Job for message {message_id} took longer than {} seconds and was aborted",
+                inner.timeout.as_secs()
+            );
+            unanswered.reason = format!(
+                "The download took longer than {} seconds and was stopped",
+                inner.timeout.as_secs()
+            );
+        }
+    }
+
+    let mut state = inner.state.lock().unwrap();
+    state.running.remove(&message_id);
+    if state.is_idle() {
+        info!("All jobs are done");
+        inner.drained.notify_waiters();
+    }
+}
+
+/// Limits how many downloads run at the same time per platform, on top of the limit of the
+/// [JobQueue]. Platforms without a limit are only limited by the queue.
+pub struct PlatformLimits {
+    limits: HashMap<String, Arc<Semaphore>>,
+}
+
+impl PlatformLimits {
+    pub fn new(limits: &HashMap<String, usize>) -> PlatformLimits {
+        let limits = limits
+            .iter()
+            .map(|(name, limit)| (name.clone(), Arc::new(Semaphore::new(*limit))))
+            .collect();
+        PlatformLimits { limits }
+    }
+
+    /// Waits until the platform has a free slot, the slot is given back when the permit drops
+    pub async fn acquire(&self, platform: &str) -> Option<OwnedSemaphorePermit> {
+        let limit = self.limits.get(platform)?;
+        limit.clone().acquire_owned().await.ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use serenity::http::Http;
+    use serenity::model::id::{MessageId, UserId};
+    use serenity::model::user::User;
+    use social_loaders::LoadOptions;
+    use tokio::sync::Notify;
+
+    use crate::config::Config;
+    use crate::handlers::task::Task;
+    use crate::jobs::{Delivery, EnqueueError, Job, JobQueue};
+    use crate::test_support::Discord;
+
+    const CONFIG: &str = r#"
+        debug = 1
+        discord_token = "token"
+        [channels_listening]
+        [downloaders]
+    "#;
+
+    fn job(message_id: u64, user_id: u64) -> Job {
+        let mut sender = User::default();
+        sender.id = UserId(user_id);
+        Job {
+            message_id: MessageId(message_id),
+            task: Task {
+                sender,
+                channel_id: Default::default(),
+                guild_id: None,
+                mentions: Vec::new(),
+                urls: Vec::new(),
+                clips: HashMap::new(),
+                content: String::new(),
+                reply: None,
+            },
+            config: Arc::new(toml::from_str::<Config>(CONFIG).unwrap()),
+            options: LoadOptions::new(8),
+            spoiler: false,
+            delivery: Delivery::Webhook,
+            http: Arc::new(Http::new("")),
+        }
+    }
+
+    /// A `/download` command whose answers go to `discord`
+    fn command_job(message_id: u64, discord: &Discord) -> Job {
+        let command = r#"{
+            "id": "1", "application_id": "2", "type": 2, "channel_id": "3",
+            "data": {"id": "4", "name": "download", "type": 1},
+            "user": {"id": "5", "username": "user", "discriminator": "0001", "avatar": null},
+            "token": "token", "version": 1, "locale": "en-US"
+        }"#;
+        let mut job = job(message_id, 5);
+        job.delivery = Delivery::Interaction(serde_json::from_str(command).unwrap());
+        job.http = discord.http.clone();
+        job
+    }
+
+    /// Jobs wait for `release` so the test decides when they finish
+    fn blocking_queue(
+        max_concurrent: usize,
+        release: Arc<Notify>,
+    ) -> (JobQueue, Arc<Mutex<Vec<u64>>>, Arc<AtomicUsize>) {
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicUsize::new(0));
+
+        let queue = {
+            let started = started.clone();
+            let max_running = max_running.clone();
+            JobQueue::new(max_concurrent, Duration::from_secs(5), move |job| {
+                let started = started.clone();
+                let max_running = max_running.clone();
+                let running = running.clone();
+                let release = release.clone();
+                async move {
+                    started.lock().unwrap().push(job.message_id.0);
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    release.notified().await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }
+            })
+        };
+        (queue, started, max_running)
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_and_fairness() {
+        let release = Arc::new(Notify::new());
+        let (queue, started, max_running) = blocking_queue(1, release.clone());
+
+        //User 1 posts three links before user 2 posts one, user 2 should not wait for all of them
+        for (message, user) in [(1, 1), (2, 1), (3, 1), (4, 2)] {
+            queue.enqueue(job(message, user)).unwrap();
+        }
+        for _ in 0..4 {
+            settle().await;
+            release.notify_one();
+        }
+
+        assert!(queue.shutdown(Duration::from_secs(1)).await);
+        assert_eq!(*started.lock().unwrap(), vec![1, 4, 2, 3]);
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_waiting_and_running_jobs() {
+        let release = Arc::new(Notify::new());
+        let (queue, started, _) = blocking_queue(1, release.clone());
+
+        queue.enqueue(job(1, 1)).unwrap();
+        queue.enqueue(job(2, 2)).unwrap();
+        settle().await;
+
+        assert!(queue.cancel(MessageId(2)));
+        assert!(queue.cancel(MessageId(1)));
+        assert!(!queue.cancel(MessageId(3)));
+
+        assert!(queue.shutdown(Duration::from_secs(1)).await);
+        assert_eq!(*started.lock().unwrap(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_accepting_and_aborts_after_grace() {
+        let release = Arc::new(Notify::new());
+        let (queue, _, _) = blocking_queue(1, release);
+
+        queue.enqueue(job(1, 1)).unwrap();
+        settle().await;
+
+        assert!(!queue.shutdown(Duration::from_millis(50)).await);
+        assert_eq!(queue.enqueue(job(2, 1)), Err(EnqueueError::ShuttingDown));
+    }
+
+    #[tokio::test]
+    async fn test_job_timeout() {
+        let queue = JobQueue::new(1, Duration::from_millis(20), |_| {
+            std::future::pending::<()>()
+        });
+        queue.enqueue(job(1, 1)).unwrap();
+
+        assert!(queue.shutdown(Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_command_is_answered_when_its_job_times_out() {
+        let discord = Discord::start();
+        let queue = JobQueue::new(1, Duration::from_millis(20), |_| {
+            std::future::pending::<()>()
+        });
+        queue.enqueue(command_job(1, &discord)).unwrap();
+
+        assert!(queue.shutdown(Duration::from_secs(1)).await);
+        let (request, body) = discord.requests(1).await.remove(0);
+        assert_eq!(request, "POST /api/v10/webhooks/2/token");
+        assert!(body.contains("took longer than"), "{body}");
+        assert!(body.contains(r#""flags":64"#), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_command_is_answered_when_the_queue_rejects_it() {
+        let discord = Discord::start();
+        let queue = JobQueue::new(1, Duration::from_secs(5), |_| async {});
+        assert!(queue.shutdown(Duration::from_secs(1)).await);
+
+        let result = queue.enqueue(command_job(1, &discord));
+        assert_eq!(result.err(), Some(EnqueueError::ShuttingDown));
+        let (_, body) = discord.requests(1).await.remove(0);
+        assert!(body.contains("restarting"), "{body}");
+    }
+}
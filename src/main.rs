@@ -2,13 +2,19 @@
 #![feature(once_cell_try)]
 #![allow(warnings)]
 
-use std::collections::HashMap;
 use std::env::current_dir;
+use std::process::exit;
+use std::sync::Arc;
 
-use crate::handlers::automatic_handler::AutomaticDownloader;
-use serde::Deserialize;
+use crate::config::Config;
+use crate::handlers::automatic_handler::{AutomaticDownloader, Processor};
+use crate::jobs::{JobQueue, PlatformLimits};
 use serenity::futures::SinkExt;
 use serenity::prelude::*;
+use social_loaders::cache::Cache;
+use social_loaders::phash::RepostStore;
+use social_loaders::Registry;
+use std::time::Duration;
 use tokio::fs;
 use tracing::info;
 use tracing::instrument::WithSubscriber;
@@ -17,44 +23,23 @@ use tracing_subscriber::fmt::writer::MakeWriterExt;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{filter, fmt};
 
+mod config;
 mod handlers;
-
-#[derive(Deserialize)]
-struct Config {
-    channels_listening: HashMap<String, String>,
-    debug: u64,
-    discord_token: String,
-    downloaders: Downloaders,
-}
-
-#[derive(Deserialize, Default)]
-struct Downloaders {
-    reddit: bool,
-    youtube: bool,
-    tiktok: bool,
-    tumblr: bool,
-}
-
-impl TypeMapKey for Config {
-    type Value = Config;
-}
-
-static _CONFIG_FILE_LOCATION: &str = "/etc/opt/gamersbot";
-static CONFIG: &str = include_str!("../properties_test.toml");
+mod jobs;
+mod reload;
+#[cfg(test)]
+mod test_support;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
-    //Parsing Toml File
-    let config = {
-        /*
-        let config_file = fs::read("resources/properties.toml").await.expect(
-            "No properties.toml file found, please provide a properties file",
-        );
-         */
-        let config_file_utf8 = std::str::from_utf8(CONFIG.as_bytes())
-            .expect("Error when parsing properties.toml into Utf8: ");
-
-        toml::from_str::<Config>(config_file_utf8).expect("Error when parsing toml file:")
+    //Parsing Toml File, the logger is not set up yet so we report errors on stderr
+    let config_path = config::locate();
+    let config = match Config::from_file(&config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Failed to load config: {err}");
+            exit(1);
+        }
     };
 
     //We have to transfer ownership of the logging guard to the main function,
@@ -72,28 +57,80 @@ async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     //        .expect("Panicked on Setting MAX_FILE_SIZE Constant");
     //}
 
+    //Downloads run in the background so a burst of links does not start dozens of yt-dlp and
+    //ffmpeg processes at once
+    let cache = config.cache.enabled.then(|| {
+        Cache::open(
+            &config.cache.path,
+            Duration::from_secs(config.cache.ttl_hours * 60 * 60),
+            config.cache.max_entries,
+        )
+    });
+    let reposts = config.reposts.enabled.then(|| {
+        RepostStore::open(
+            &config.reposts.path,
+            config.reposts.threshold,
+            config.reposts.max_per_guild,
+        )
+    });
+    let processor = Arc::new(Processor::new(
+        Registry::with_builtin(),
+        PlatformLimits::new(&config.jobs.per_platform),
+        cache,
+        reposts,
+    ));
+    let queue = JobQueue::new(
+        config.jobs.max_concurrent,
+        Duration::from_secs(config.jobs.timeout_secs),
+        move |job| {
+            let processor = processor.clone();
+            async move { processor.run(job).await }
+        },
+    );
+    let shutdown_grace = Duration::from_secs(config.jobs.shutdown_grace_secs);
+
     //Setup Client
     let mut client = {
         // Set gateway intents, which decides what events the bot will be notified about
-        let intents = GatewayIntents::GUILD_MESSAGES
+        //GUILDS is needed for the boost tier of the guilds which decides the upload limit
+        let intents = GatewayIntents::GUILDS
+            | GatewayIntents::GUILD_MESSAGES
             | GatewayIntents::DIRECT_MESSAGES
             | GatewayIntents::MESSAGE_CONTENT;
 
         // Configure the client with your Discord bot token in the environment.
         //let token = env::var("DISCORD_TOKEN").expect("Expected a token in the
         // environment");
-        let token = &config.discord_token;
+        let token = config.discord_token.clone();
 
         // Create a new instance of the Client, logging in as a bot. This will
         // automatically prepend your bot token with "Bot ", which is a requirement
         // by Discord for bot users.
         Client::builder(token, intents)
-            .event_handler(AutomaticDownloader)
-            .type_map_insert::<Config>(config)
+            .event_handler(AutomaticDownloader::new(
+                queue.clone(),
+                Registry::with_builtin(),
+            ))
+            .type_map_insert::<Config>(Arc::new(config))
             .await
             .expect("Err creating client")
     };
 
+    reload::spawn_watcher(
+        config_path,
+        client.data.clone(),
+        client.cache_and_http.http.clone(),
+    );
+
+    //On ctrl-c or SIGTERM we let the running downloads finish before we disconnect
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down, waiting for running downloads");
+        queue.shutdown(shutdown_grace).await;
+        shard_manager.lock().await.shutdown_all().await;
+    });
+
     // Finally, start a single shard, and start listening to events.
     // Shards will automatically attempt to reconnect, and will perform
     // exponential backoff until it reconnects.
@@ -104,6 +141,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     Ok(())
 }
 
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 /// Returns the Logging Guard which has to be held in Scope to not Drop it, dropping the Logging
 /// Guard flushes the current events captures by the logger into the File and makes the Logger
 /// unavailable for the rest duration of the Program
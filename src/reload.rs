@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+use serenity::prelude::{RwLock, TypeMap};
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+
+/// How often the modification time of the config file is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watches the config file and reloads it when it changes or the process receives a SIGHUP.
+/// The new config is only swapped into the TypeMap when it is valid, otherwise the old one
+/// stays active and the error is posted to the debug channel. The `[jobs]`, `[cache]` and
+/// `[reposts]` tables are only read at startup, a reload does not change them.
+pub fn spawn_watcher(path: PathBuf, data: Arc<RwLock<TypeMap>>, http: Arc<Http>) {
+    tokio::spawn(async move {
+        let mut hangup = Hangup::new();
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut last_modified = modified(&path);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let modified = modified(&path);
+                    if modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+                    info!("Config file {} changed, reloading", path.display());
+                }
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading config from {}", path.display());
+                }
+            }
+
+            reload(&path, &data, &http).await;
+        }
+    });
+}
+
+async fn reload(path: &Path, data: &RwLock<TypeMap>, http: &Http) {
+    match Config::from_file(path) {
+        Ok(config) => {
+            let mut data = data.write().await;
+            if let Some(old) = data.get::<Config>() {
+                if old.discord_token != config.discord_token {
+                    warn!("discord_token changed, the new token is only used after a restart");
+                }
+            }
+            data.insert::<Config>(Arc::new(config));
+            info!("Reloaded config from {}", path.display());
+        }
+        Err(err) => {
+            error!("Reloading config failed, keeping the old one: {err}");
+            let debug_channel = data.read().await.get::<Config>().map(|c| c.debug);
+            if let Some(debug_channel) = debug_channel {
+                let message = format!("Reloading the config failed, keeping the old one: {err}");
+                let _ = ChannelId(debug_channel).say(http, message).await;
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Hangup {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let signal = signal(SignalKind::hangup())
+            .map_err(|err| error!("Could not listen for SIGHUP: {err}"))
+            .ok();
+        Hangup(signal)
+    }
+
+    async fn recv(&mut self) {
+        match &mut self.0 {
+            Some(signal) => {
+                signal.recv().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Hangup {
+        Hangup
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use serenity::http::Http;
+    use serenity::prelude::{RwLock, TypeMap};
+
+    use crate::config::Config;
+    use crate::reload::reload;
+    use crate::test_support::Discord;
+
+    const CONFIG: &str = r#"
+        debug = 42
+        discord_token = "token"
+        channels_listening = [123]
+
+        [downloaders]
+        reddit = true
+    "#;
+
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gamersbot_reload_{name}.toml"));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// The config the bot started with
+    fn data_with(path: &Path) -> RwLock<TypeMap> {
+        let mut data = TypeMap::new();
+        data.insert::<Config>(Arc::new(Config::from_file(path).unwrap()));
+        RwLock::new(data)
+    }
+
+    #[tokio::test]
+    async fn test_valid_config_is_swapped_in() {
+        let path = config_file("valid", CONFIG);
+        let data = data_with(&path);
+        std::fs::write(&path, CONFIG.replace("debug = 42", "debug = 7")).unwrap();
+
+        reload(&path, &data, &Http::new("")).await;
+        assert_eq!(data.read().await.get::<Config>().unwrap().debug, 7);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_config_keeps_the_old_one_and_is_reported() {
+        let path = config_file("invalid", CONFIG);
+        let data = data_with(&path);
+        std::fs::write(&path, CONFIG.replace("channels_listening = [123]", "")).unwrap();
+
+        let discord = Discord::start();
+        reload(&path, &data, &discord.http).await;
+        let config = data.read().await.get::<Config>().unwrap().clone();
+        assert!(config.channels_listening.contains_key("123"));
+
+        let (request, body) = discord.requests(1).await.remove(0);
+        assert_eq!(request, "POST /api/v10/channels/42/messages");
+        assert!(body.contains("Reloading the config failed"), "{body}");
+        let _ = std::fs::remove_file(&path);
+    }
+}
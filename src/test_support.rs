@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serenity::http::{Http, HttpBuilder};
+
+/// A local server in place of the Discord api. It answers the requests registered with
+/// [answer](Discord::answer) and every other one with a 404.
+pub struct Discord {
+    /// Sends its requests to the server, its application has the id 2
+    pub http: Arc<Http>,
+    routes: Arc<Mutex<HashMap<String, String>>>,
+    requests: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl Discord {
+    pub fn start() -> Discord {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let (server_routes, server_requests) = (routes.clone(), requests.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = answer(stream, &server_routes, &server_requests);
+            }
+        });
+
+        let http = HttpBuilder::new("token")
+            .proxy(format!("http://{address}"))
+            .unwrap()
+            .application_id(2)
+            .ratelimiter_disabled(true)
+            .build();
+        Discord {
+            http: Arc::new(http),
+            routes,
+            requests,
+        }
+    }
+
+    /// Answers `request`, like `GET /api/v10/users/@me`, with the json `body`
+    pub fn answer(&self, request: &str, body: &str) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.insert(request.to_string(), body.to_string());
+    }
+
+    /// Waits until there were `count` requests and returns all requests so far, as
+    /// `METHOD /path` together with the body
+    pub async fn requests(&self, count: usize) -> Vec<(String, String)> {
+        for _ in 0..500 {
+            if self.requests.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn answer(
+    stream: TcpStream,
+    routes: &Mutex<HashMap<String, String>>,
+    requests: &Mutex<Vec<(String, String)>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    //The query is not part of the request, `?wait=true` of webhooks for example
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts
+        .next()
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    let request = format!("{method} {path}");
+    let answer = routes.lock().unwrap().get(&request).cloned();
+    requests
+        .lock()
+        .unwrap()
+        .push((request, String::from_utf8_lossy(&body).into_owned()));
+
+    let mut stream = stream;
+    match answer {
+        Some(body) => write!(
+            stream,
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\
+             connection: close\r\n\r\n{body}",
+            body.len()
+        )?,
+        None => write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+        )?,
+    }
+    stream.flush()
+}
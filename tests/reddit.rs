@@ -0,0 +1,265 @@
+#![cfg(unix)]
+
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use reqwest::Client;
+use social_loaders::loaderror::LoadError;
+use social_loaders::reddit::Reddit;
+use social_loaders::{AudioFormat, Downloader, LoadOptions, Notes};
+use url::Url;
+
+use crate::support::{fixture, install_stubs, remove_files, MockServer};
+
+mod support;
+
+/// A server that answers for reddit, with the media urls of the fixture pointing to it as well
+fn server_with_post(fixture_name: &str, post_id: &str) -> (MockServer, Reddit) {
+    install_stubs();
+    let server = MockServer::start();
+    let post = fixture(fixture_name)
+        .replace("https://i.redd.it/", &server.url("i/"))
+        .replace("https://v.redd.it/", &server.url("v/"));
+    server.serve(&format!("comments/{post_id}/.json"), post);
+
+    let reddit = Reddit::new(Client::new(), server.base_url())
+        .with_media_url(Url::parse(&server.url("i/")).unwrap());
+    (server, reddit)
+}
+
+fn encode(format: ImageOutputFormat) -> Vec<u8> {
+    let image = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+        image::Rgb([x as u8 * 16, y as u8 * 16, 128])
+    }));
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, format).unwrap();
+    bytes.into_inner()
+}
+
+/// Serves the DASH playlist of the video fixture and every track with `track_size` bytes
+fn serve_video_tracks(server: &MockServer, track_size: usize) {
+    server.serve(
+        "v/a1b2c3d4e5f6g/DASHPlaylist.mpd",
+        fixture("reddit_dash/DASHPlaylist.mpd"),
+    );
+    for track in [
+        "DASH_220.mp4",
+        "DASH_480.mp4",
+        "DASH_720.mp4",
+        "DASH_1080.mp4",
+        "DASH_AUDIO_64.mp4",
+        "DASH_AUDIO_128.mp4",
+    ] {
+        server.serve(&format!("v/a1b2c3d4e5f6g/{track}"), vec![0u8; track_size]);
+    }
+}
+
+fn post_url(post_id: &str) -> Url {
+    Url::parse(&format!(
+        "https://www.reddit.com/r/memes/comments/{post_id}/title/"
+    ))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_load_image() {
+    let (server, reddit) = server_with_post("reddit/image.json", "141abcd");
+    server.serve("i/5xk2q8y0vb4b1.jpg", encode(ImageOutputFormat::Jpeg(80)));
+
+    let paths = reddit
+        .load(&post_url("141abcd"), &LoadOptions::new(8))
+        .await
+        .unwrap();
+    assert_eq!(paths.len(), 1);
+    assert!(paths[0].exists());
+    assert_eq!(paths[0].extension().unwrap(), "jpg");
+    remove_files(&paths);
+}
+
+#[tokio::test]
+async fn test_missing_image_is_not_found() {
+    let (_server, reddit) = server_with_post("reddit/image.json", "141abcd");
+
+    let result = reddit
+        .load(&post_url("141abcd"), &LoadOptions::new(8))
+        .await;
+    assert!(matches!(result, Err(LoadError::NotFound)), "{result:?}");
+}
+
+#[tokio::test]
+async fn test_load_gallery_in_order() {
+    let (server, reddit) = server_with_post("reddit/gallery.json", "143ijkl");
+    server.serve("i/q9w8e7r6t5y4.jpg", encode(ImageOutputFormat::Jpeg(80)));
+    server.serve("i/a1s2d3f4g5h6.png", encode(ImageOutputFormat::Png));
+    server.serve("i/z1x2c3v4b5n6.gif", encode(ImageOutputFormat::Gif));
+
+    let paths = reddit
+        .load(&post_url("143ijkl"), &LoadOptions::new(8))
+        .await
+        .unwrap();
+    let extensions = paths
+        .iter()
+        .map(|path| path.extension().unwrap().to_str().unwrap())
+        .collect::<Vec<&str>>();
+    //The item that failed on reddit is skipped and the gif is converted
+    assert_eq!(extensions, vec!["jpg", "png", "mp4"]);
+    assert!(paths.iter().all(|path| path.exists()));
+    remove_files(&paths);
+    remove_files(&[paths[2].with_extension("gif")]);
+}
+
+#[tokio::test]
+async fn test_gallery_with_a_missing_image_is_not_found() {
+    let (server, reddit) = server_with_post("reddit/gallery.json", "143ijkl");
+    server.serve("i/q9w8e7r6t5y4.jpg", encode(ImageOutputFormat::Jpeg(80)));
+
+    let result = reddit
+        .load(&post_url("143ijkl"), &LoadOptions::new(8))
+        .await;
+    assert!(matches!(result, Err(LoadError::NotFound)), "{result:?}");
+    assert!(server
+        .requests()
+        .contains(&"GET /i/a1s2d3f4g5h6.png".to_string()));
+}
+
+#[tokio::test]
+async fn test_load_gif_converts_to_mp4() {
+    let (server, reddit) = server_with_post("reddit/gif.json", "145qrst");
+    server.serve("i/g1f2g3f4g5f6.gif", encode(ImageOutputFormat::Gif));
+
+    let paths = reddit
+        .load(&post_url("145qrst"), &LoadOptions::new(8))
+        .await
+        .unwrap();
+    assert_eq!(paths.len(), 1);
+    assert_eq!(paths[0].extension().unwrap(), "mp4");
+    assert!(paths[0].exists());
+    remove_files(&paths);
+    remove_files(&[paths[0].with_extension("gif")]);
+}
+
+#[tokio::test]
+async fn test_load_video_merges_best_tracks() {
+    let (server, reddit) = server_with_post("reddit/video.json", "142efgh");
+    serve_video_tracks(&server, 1000);
+
+    let paths = reddit
+        .load(&post_url("142efgh"), &LoadOptions::new(8))
+        .await
+        .unwrap();
+    assert_eq!(paths.len(), 1);
+    assert!(paths[0].exists());
+
+    let requests = server.requests();
+    assert!(requests.contains(&"GET /v/a1b2c3d4e5f6g/DASH_1080.mp4".to_string()));
+    assert!(requests.contains(&"GET /v/a1b2c3d4e5f6g/DASH_AUDIO_128.mp4".to_string()));
+    assert!(!requests.contains(&"GET /v/a1b2c3d4e5f6g/DASH_720.mp4".to_string()));
+    remove_files(&paths);
+}
+
+#[tokio::test]
+async fn test_load_video_audio_only() {
+    let (server, reddit) = server_with_post("reddit/video.json", "142efgh");
+    serve_video_tracks(&server, 1000);
+
+    let mut options = LoadOptions::new(8);
+    options.audio = Some(AudioFormat::Mp3);
+    let paths = reddit.load(&post_url("142efgh"), &options).await.unwrap();
+    assert_eq!(paths[0].extension().unwrap(), "mp3");
+    assert!(paths[0].exists());
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|request| request.starts_with("GET") && request.contains("DASH_1080")));
+    remove_files(&paths);
+}
+
+#[tokio::test]
+async fn test_reject_oversized_video() {
+    let (server, reddit) = server_with_post("reddit/video.json", "142efgh");
+    serve_video_tracks(&server, 1_100_000);
+
+    let result = reddit
+        .load(&post_url("142efgh"), &LoadOptions::new(1))
+        .await;
+    assert!(matches!(
+        result,
+        Err(LoadError::TooLarge {
+            size: Some(_),
+            limit: 1_000_000
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_video_loses_its_sound_only_as_last_resort() {
+    //Every track is 600kB, a video with sound does not fit into 1MB
+    let (server, reddit) = server_with_post("reddit/video.json", "142efgh");
+    serve_video_tracks(&server, 600_000);
+    let got_audio = |server: &MockServer| {
+        server
+            .requests()
+            .iter()
+            .any(|request| request.starts_with("GET") && request.contains("DASH_AUDIO"))
+    };
+
+    let notes = Notes::default();
+    let mut options = LoadOptions::new(1);
+    options.notes = Some(notes.clone());
+    let paths = reddit.load(&post_url("142efgh"), &options).await.unwrap();
+    assert!(!got_audio(&server));
+    assert_eq!(notes.take().0.len(), 1);
+    remove_files(&paths);
+
+    //A video that gets compressed keeps its sound
+    let (server, reddit) = server_with_post("reddit/video.json", "142efgh");
+    serve_video_tracks(&server, 600_000);
+    let mut options = LoadOptions::new(1);
+    options.notes = Some(notes.clone());
+    options.compress = true;
+    let paths = reddit.load(&post_url("142efgh"), &options).await.unwrap();
+    assert!(got_audio(&server));
+    assert!(notes.take().0.is_empty());
+    remove_files(&paths);
+}
+
+#[tokio::test]
+async fn test_unreachable_fallback_video_is_not_too_large() {
+    //Neither the playlist nor the fallback video exist, there is no size to compare
+    let (_server, reddit) = server_with_post("reddit/video.json", "142efgh");
+
+    let result = reddit
+        .load(&post_url("142efgh"), &LoadOptions::new(8))
+        .await;
+    assert!(matches!(result, Err(LoadError::NotFound)), "{result:?}");
+}
+
+#[tokio::test]
+async fn test_ignore_text_post() {
+    let (_server, reddit) = server_with_post("reddit/text.json", "144mnop");
+
+    let result = reddit
+        .load(&post_url("144mnop"), &LoadOptions::new(8))
+        .await;
+    assert!(matches!(result, Err(LoadError::Ignore(_))));
+}
+
+#[tokio::test]
+async fn test_malformed_post_is_an_error() {
+    let (_server, reddit) = server_with_post("reddit/malformed.json", "147yzab");
+
+    let result = reddit
+        .load(&post_url("147yzab"), &LoadOptions::new(8))
+        .await;
+    assert!(matches!(result, Err(LoadError::Internal(_))));
+}
+
+#[tokio::test]
+async fn test_missing_post_is_not_found() {
+    let (_server, reddit) = server_with_post("reddit/image.json", "141abcd");
+
+    let result = reddit
+        .load(&post_url("999zzzz"), &LoadOptions::new(8))
+        .await;
+    assert!(matches!(result, Err(LoadError::NotFound)));
+}
@@ -0,0 +1,171 @@
+//! What the integration tests share: a local http server that answers with fixtures and stub
+//! versions of yt-dlp and ffmpeg, so the loaders can be tested without network or tools
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use url::Url;
+
+/// Answers every request with the body registered for its path, the query is ignored.
+/// Unknown paths get a 404.
+pub struct MockServer {
+    base_url: Url,
+    routes: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    pub fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let (server_routes, server_requests) = (routes.clone(), requests.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = answer(stream, &server_routes, &server_requests);
+            }
+        });
+
+        MockServer {
+            base_url,
+            routes,
+            requests,
+        }
+    }
+
+    /// Absolute url of `path` on this server
+    pub fn url(&self, path: &str) -> String {
+        self.base_url.join(path).unwrap().to_string()
+    }
+
+    pub fn base_url(&self) -> Url {
+        self.base_url.clone()
+    }
+
+    pub fn serve(&self, path: &str, body: impl Into<Vec<u8>>) {
+        let path = format!("/{}", path.trim_start_matches('/'));
+        self.routes.lock().unwrap().insert(path, body.into());
+    }
+
+    /// Every request so far as `METHOD /path`
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn answer(
+    stream: TcpStream,
+    routes: &Mutex<HashMap<String, Vec<u8>>>,
+    requests: &Mutex<Vec<String>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    //The headers are not needed, they only have to be read before we answer
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts
+        .next()
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    requests.lock().unwrap().push(format!("{method} {path}"));
+
+    let body = routes.lock().unwrap().get(&path).cloned();
+    let mut stream = stream;
+    match body {
+        Some(body) => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )?;
+            if method != "HEAD" {
+                stream.write_all(&body)?;
+            }
+        }
+        None => write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?,
+    }
+    stream.flush()
+}
+
+/// Writes its last argument, the output file, like ffmpeg does
+const FFMPEG: &str = r#"#!/bin/sh
+for last in "$@"; do :; done
+printf 'ffmpeg stub' > "$last"
+"#;
+
+/// Creates the file given with `-o` with the extension of `--audio-format`, the file contains the
+/// arguments so tests can check what was downloaded. Links containing `fail` make it exit like a
+/// failed download. With `--progress` it prints progress lines, `--dump-json` prints the
+/// TikTok video fixture.
+const YT_DLP: &str = r#"#!/bin/sh
+case "$*" in
+  *fail*) echo "ERROR: stub download failed" >&2; exit 1 ;;
+  *--dump-json*) cat "$(dirname "$0")/dump.json"; exit 0 ;;
+esac
+args="$*"
+extension=mp4
+while [ $# -gt 0 ]; do
+  if [ "$1" = "-o" ]; then output="$2"; fi
+  if [ "$1" = "--audio-format" ]; then extension="$2"; fi
+  if [ "$1" = "--progress" ]; then progress=1; fi
+  shift
+done
+if [ -n "$progress" ]; then
+  printf '[download]  50.0%% of ~   1.00MiB at  1.00MiB/s ETA 00:01\n'
+  printf '[download] 100%% of    1.00MiB in 00:00:01\n'
+fi
+output=$(printf '%s' "$output" | sed "s/%(ext)s/$extension/")
+printf 'yt-dlp stub %s' "$args" > "$output"
+"#;
+
+/// Points the loaders at the stub scripts instead of the real programs
+pub fn install_stubs() {
+    static STUBS: OnceLock<()> = OnceLock::new();
+    STUBS.get_or_init(|| {
+        let dir = env::temp_dir().join(format!("social_loaders_stubs_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, script) in [("ffmpeg", FFMPEG), ("yt-dlp", YT_DLP)] {
+            let path = dir.join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(dir.join("dump.json"), fixture("tiktok/video.json")).unwrap();
+        env::set_var("SOCIAL_LOADERS_FFMPEG", dir.join("ffmpeg"));
+        env::set_var("SOCIAL_LOADERS_YT_DLP", dir.join("yt-dlp"));
+    });
+}
+
+pub fn fixture(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    fs::read_to_string(&path).unwrap_or_else(|_| panic!("Missing fixture {}", path.display()))
+}
+
+pub fn remove_files(paths: &[PathBuf]) {
+    for path in paths {
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,40 @@
+#![cfg(unix)]
+
+use std::fs;
+
+use social_loaders::loaderror::LoadError;
+use social_loaders::tiktok;
+use social_loaders::LoadOptions;
+use url::Url;
+
+use crate::support::{install_stubs, remove_files};
+
+mod support;
+
+#[tokio::test]
+async fn test_load_video_in_the_format_that_fits() {
+    install_stubs();
+    let url = Url::parse("https://www.tiktok.com/@user/video/7234567890123456789").unwrap();
+
+    //The 1080p variant of the fixture is too large for 2MB
+    let path = tiktok::load(&url, &LoadOptions::new(2)).await.unwrap();
+    let args = fs::read_to_string(&path).unwrap();
+    assert!(args.contains("-f h264_540p_666613-0"), "{args}");
+    assert!(args.contains("7234567890123456789"), "{args}");
+    remove_files(&[path]);
+}
+
+#[tokio::test]
+async fn test_video_too_large_for_every_format() {
+    install_stubs();
+    let url = Url::parse("https://www.tiktok.com/@user/video/7234567890123456789").unwrap();
+
+    let result = tiktok::load(&url, &LoadOptions::new(1)).await;
+    assert!(matches!(
+        result,
+        Err(LoadError::TooLarge {
+            limit: 1_000_000,
+            ..
+        })
+    ));
+}
@@ -0,0 +1,102 @@
+#![cfg(unix)]
+
+use reqwest::Client;
+use social_loaders::loaderror::LoadError;
+use social_loaders::tumblr::Tumblr;
+use social_loaders::{Downloader, LoadOptions, Notes};
+use url::Url;
+
+use crate::support::{fixture, install_stubs, remove_files, MockServer};
+
+mod support;
+
+/// A server that answers for tumblr, with the media urls of the fixture pointing to it as well
+fn server_with_post() -> (MockServer, Tumblr) {
+    install_stubs();
+    let server = MockServer::start();
+    let page = fixture("tumblr/post.html")
+        .replace("https://64.media.tumblr.com/", &server.url("64/"))
+        .replace("https://va.media.tumblr.com/", &server.url("va/"));
+    server.serve("staff/713482013426", page);
+    server.serve("64/abc/s2048x3072/original.jpg", vec![0u8; 1000]);
+    server.serve("64/def/s540x810/dancing.gif", vec![0u8; 1000]);
+
+    let tumblr = Tumblr::new(Client::new(), server.base_url());
+    (server, tumblr)
+}
+
+fn post_url() -> Url {
+    Url::parse("https://www.tumblr.com/staff/713482013426").unwrap()
+}
+
+#[tokio::test]
+async fn test_load_post() {
+    let (server, tumblr) = server_with_post();
+    server.serve("va/tumblr_xyz.mp4", vec![0u8; 1000]);
+
+    let paths = tumblr
+        .load(&post_url(), &LoadOptions::new(8))
+        .await
+        .unwrap();
+    let extensions = paths
+        .iter()
+        .map(|path| path.extension().unwrap().to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(extensions, vec!["jpg", "mp4", "mp4"]);
+    assert!(paths.iter().all(|path| path.exists()));
+    //The gif is replaced by the converted video
+    assert!(!paths[1].with_extension("gif").exists());
+    remove_files(&paths);
+}
+
+#[tokio::test]
+async fn test_oversized_media_is_refused() {
+    let (server, tumblr) = server_with_post();
+    server.serve("va/tumblr_xyz.mp4", vec![0u8; 2_000_000]);
+
+    let err = tumblr
+        .load(&post_url(), &LoadOptions::new(1))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            LoadError::TooLarge {
+                size: Some(2_000_000),
+                limit: 1_000_000
+            }
+        ),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_oversized_video_is_loaded_for_compression() {
+    let (server, tumblr) = server_with_post();
+    server.serve("va/tumblr_xyz.mp4", vec![0u8; 2_000_000]);
+
+    let mut options = LoadOptions::new(1);
+    options.compress = true;
+    let paths = tumblr.load(&post_url(), &options).await.unwrap();
+    assert_eq!(paths.len(), 3);
+    assert_eq!(paths[2].metadata().unwrap().len(), 2_000_000);
+    remove_files(&paths);
+}
+
+#[tokio::test]
+async fn test_rest_of_the_post_is_loaded_when_one_file_fails() {
+    let (_server, tumblr) = server_with_post();
+
+    let notes = Notes::default();
+    let mut options = LoadOptions::new(8);
+    options.notes = Some(notes.clone());
+    let paths = tumblr.load(&post_url(), &options).await.unwrap();
+    assert_eq!(paths.len(), 2);
+    let (_, failed) = notes.take();
+    assert!(matches!(failed[..], [LoadError::NotFound]), "{failed:?}");
+    remove_files(&paths);
+
+    //Without notes nobody would learn about the missing video
+    let result = tumblr.load(&post_url(), &LoadOptions::new(8)).await;
+    assert!(matches!(result, Err(LoadError::NotFound)), "{result:?}");
+}
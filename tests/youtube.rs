@@ -0,0 +1,89 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use social_loaders::loaderror::LoadError;
+use social_loaders::youtube;
+use social_loaders::{AudioFormat, LoadOptions, Progress};
+use url::Url;
+
+use crate::support::{install_stubs, remove_files};
+
+mod support;
+
+#[tokio::test]
+async fn test_load_video_with_stub() {
+    install_stubs();
+    let url = Url::parse("https://www.youtube.com/watch?v=TK4N5W22Gts&t=90").unwrap();
+
+    let path = youtube::load(&url, &LoadOptions::new(8)).await.unwrap();
+    assert_eq!(path.extension().unwrap(), "mp4");
+    assert!(path.exists());
+    remove_files(&[path]);
+}
+
+#[tokio::test]
+async fn test_load_every_link_form_with_stub() {
+    install_stubs();
+    for (link, id) in [
+        ("https://www.youtube.com/shorts/B1j3yeHRKbY", "B1j3yeHRKbY"),
+        ("https://www.youtube.com/watch?v=TK4N5W22Gts", "TK4N5W22Gts"),
+        ("https://youtu.be/UT5F9AXjwhg", "UT5F9AXjwhg"),
+    ] {
+        let url = Url::parse(link).unwrap();
+        let path = youtube::load(&url, &LoadOptions::new(25)).await.unwrap();
+        //The stub writes what it was asked to download into the file
+        let args = fs::read_to_string(&path).unwrap();
+        assert!(args.contains(id), "{link}: {args}");
+        assert!(args.contains("filesize~24M"), "{link}: {args}");
+        remove_files(&[path]);
+    }
+}
+
+#[tokio::test]
+async fn test_load_audio_with_stub() {
+    install_stubs();
+    let url = Url::parse("https://youtu.be/UT5F9AXjwhg").unwrap();
+
+    let mut options = LoadOptions::new(8);
+    options.audio = Some(AudioFormat::M4a);
+    options.end = Some(Duration::from_secs(30));
+    let path = youtube::load(&url, &options).await.unwrap();
+    assert_eq!(path.extension().unwrap(), "m4a");
+    assert!(path.exists());
+    remove_files(&[path]);
+}
+
+#[tokio::test]
+async fn test_progress_is_reported() {
+    install_stubs();
+    let url = Url::parse("https://www.youtube.com/watch?v=TK4N5W22Gts").unwrap();
+
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let mut options = LoadOptions::new(8);
+    let sink = reported.clone();
+    options.progress = Some(Progress::new(move |percent| {
+        sink.lock().unwrap().push(percent)
+    }));
+    let path = youtube::load(&url, &options).await.unwrap();
+    assert_eq!(*reported.lock().unwrap(), vec![50, 100]);
+    remove_files(&[path]);
+}
+
+#[tokio::test]
+async fn test_failed_download_is_a_tool_error() {
+    install_stubs();
+    let url = Url::parse("https://www.youtube.com/watch?v=fail").unwrap();
+
+    let result = youtube::load(&url, &LoadOptions::new(8)).await;
+    assert!(matches!(
+        result,
+        Err(LoadError::ToolFailed {
+            tool: "yt-dlp",
+            exit_code: Some(1),
+            ..
+        })
+    ));
+}